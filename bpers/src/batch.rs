@@ -0,0 +1,143 @@
+use std::{num::NonZeroUsize, thread};
+
+use crate::{AllowedSpecial, Encoder, EncodingError, Vocabulary, decode};
+
+/// Encodes a batch of input strings in parallel.
+///
+/// # Arguments
+/// * `inputs` - The documents to encode.
+/// * `vocab` - The vocabulary to use for encoding.
+///
+/// # Returns
+/// The encoding of every input, in input order. A document that fails to encode does not affect
+/// the others.
+pub fn encode_batch<S>(inputs: &[S], vocab: &Vocabulary) -> Vec<Result<Vec<u32>, EncodingError>>
+where
+    S: AsRef<str> + Sync,
+{
+    encode_batch_with_special(inputs, vocab, &AllowedSpecial::None)
+}
+
+/// Encodes a batch of input strings in parallel, turning the literal text of allowed special
+/// tokens into their ids.
+///
+/// The threads share one [`Encoder`], so words repeated across documents are merged only once.
+///
+/// # Arguments
+/// * `inputs` - The documents to encode.
+/// * `vocab` - The vocabulary to use for encoding.
+/// * `allowed` - The special tokens that may appear in the inputs.
+///
+/// # Returns
+/// The encoding of every input, in input order. A document that fails to encode does not affect
+/// the others.
+pub fn encode_batch_with_special<S>(
+    inputs: &[S],
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+) -> Vec<Result<Vec<u32>, EncodingError>>
+where
+    S: AsRef<str> + Sync,
+{
+    let encoder = Encoder::new(vocab);
+    map_parallel(inputs, |input| {
+        encoder.encode_with_special(input.as_ref(), allowed)
+    })
+}
+
+/// Decodes a batch of token id sequences in parallel.
+///
+/// # Arguments
+/// * `token_ids` - The encoded documents to decode.
+/// * `vocab` - The vocabulary to use for decoding.
+///
+/// # Returns
+/// The decoded text of every document, in input order. A document that fails to decode does not
+/// affect the others.
+pub fn decode_batch<T>(token_ids: &[T], vocab: &Vocabulary) -> Vec<Result<String, EncodingError>>
+where
+    T: AsRef<[u32]> + Sync,
+{
+    map_parallel(token_ids, |ids| decode(ids.as_ref(), vocab))
+}
+
+/// Applies `f` to every item on as many threads as there are cores, keeping the item order.
+pub(crate) fn map_parallel<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let n_threads = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(items.len());
+    if n_threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(n_threads);
+    let f = &f;
+    thread::scope(|scope| {
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PreTokenizer, encode};
+
+    #[test]
+    fn batch_results_are_in_input_order() {
+        let text = "the cat sat on the mat and the cat ate the rat";
+        let mut vocab = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        vocab.learn(text, 20);
+
+        let mut inputs = (0..100)
+            .map(|i| {
+                text.split(' ')
+                    .cycle()
+                    .skip(i)
+                    .take(i % 7 + 1)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        inputs[42] = "the dog".to_string();
+
+        let encoded = encode_batch(&inputs, &vocab);
+        assert_eq!(encoded.len(), inputs.len());
+        for (input, result) in inputs.iter().zip(&encoded) {
+            match encode(input, &vocab) {
+                Ok(expected) => assert_eq!(result.as_ref().unwrap(), &expected),
+                Err(_) => assert!(result.is_err()),
+            }
+        }
+        assert!(encoded[42].is_err());
+
+        let token_ids = encoded
+            .into_iter()
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+        let decoded = decode_batch(&token_ids, &vocab);
+        let expected = inputs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 42)
+            .map(|(_, input)| input);
+        for (result, input) in decoded.into_iter().zip(expected) {
+            assert_eq!(&result.unwrap(), input);
+        }
+    }
+}
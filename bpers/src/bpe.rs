@@ -1,6 +1,10 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use foldhash::HashMap;
+
 use thiserror::Error;
 
-use crate::{Pair, Token, Vocabulary};
+use crate::{AllowedSpecial, Alphabet, Dropout, Pair, Token, Vocabulary, special::Segment};
 
 #[derive(Error, Debug)]
 pub enum EncodingError {
@@ -10,11 +14,17 @@ pub enum EncodingError {
     InvalidChar { code: u32 },
     #[error("Unknown token with code {code}")]
     UnknownToken { code: u32 },
+    #[error("Invalid UTF-8 sequence")]
+    InvalidUtf8,
+    #[error("Input contains special token '{text}' which is not allowed")]
+    DisallowedSpecial { text: String },
 }
 
 /// Encodes an input string into a sequence of token IDs using a pre-learned vocabulary.
 ///
-/// This function applies the merge rules defined in the vocabulary greedily.
+/// The input is split with the pre-tokenizer of the vocabulary first, then the merge rules defined
+/// in the vocabulary are applied greedily to every piece. No special tokens are allowed in the
+/// input, see [`encode_with_special`].
 ///
 /// # Arguments
 /// * `input` - The string to encode.
@@ -23,42 +33,360 @@ pub enum EncodingError {
 /// # Returns
 /// A `Vec<u32>` representing the encoded token sequence, or an error if unknown characters are encountered.
 pub fn encode(input: &str, vocab: &Vocabulary) -> Result<Vec<u32>, EncodingError> {
-    let mut tokens: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    encode_with_special(input, vocab, &AllowedSpecial::None)
+}
+
+/// Encodes an input string, turning the literal text of allowed special tokens into their ids.
+///
+/// # Arguments
+/// * `input` - The string to encode.
+/// * `vocab` - A reference to the `Vocabulary` containing the learned merge rules.
+/// * `allowed` - The special tokens that may occur in the `input`.
+///
+/// # Returns
+/// A `Vec<u32>` representing the encoded token sequence, or an error if unknown characters or
+/// special tokens that are not allowed are encountered.
+pub fn encode_with_special(
+    input: &str,
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+) -> Result<Vec<u32>, EncodingError> {
+    let mut encoded = Vec::with_capacity(input.len());
+    encode_segments(input, vocab, allowed, &mut encoded, &mut |tokens| {
+        merge_tokens(tokens, vocab)
+    })?;
+    Ok(encoded)
+}
+
+/// Encodes an input string with BPE-dropout, skipping every applicable merge with the
+/// probability of `dropout`.
+///
+/// The same seed gives the same encoding. The result may be longer than the one of
+/// [`encode_with_special`], but always decodes to the input.
+///
+/// # Arguments
+/// * `input` - The string to encode.
+/// * `vocab` - A reference to the `Vocabulary` containing the learned merge rules.
+/// * `allowed` - The special tokens that may occur in the `input`.
+/// * `dropout` - The dropout probability and the state of its random number generator.
+///
+/// # Returns
+/// A `Vec<u32>` representing the encoded token sequence, or an error if unknown characters or
+/// special tokens that are not allowed are encountered.
+pub fn encode_with_dropout(
+    input: &str,
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+    dropout: &mut Dropout,
+) -> Result<Vec<u32>, EncodingError> {
+    let mut encoded = Vec::with_capacity(input.len());
+    encode_segments(input, vocab, allowed, &mut encoded, &mut |tokens| {
+        merge_tokens_with_dropout(tokens, vocab, dropout)
+    })?;
+    Ok(encoded)
+}
+
+/// Encodes the text and special tokens of `input`, merging every piece of text with `merge`.
+pub(crate) fn encode_segments(
+    input: &str,
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+    encoded: &mut Vec<u32>,
+    merge: &mut impl FnMut(Vec<u32>) -> Vec<u32>,
+) -> Result<(), EncodingError> {
+    for segment in vocab.split_specials(input) {
+        match segment {
+            Segment::Text(text) => encode_text(text, vocab, encoded, merge)?,
+            Segment::Special(text) if allowed.allows(text) => {
+                encoded.extend(vocab.special_token_id(text));
+            }
+            Segment::Special(text) => {
+                return Err(EncodingError::DisallowedSpecial {
+                    text: text.to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encodes an input string treating the text of special tokens like any other text.
+///
+/// # Arguments
+/// * `input` - The string to encode.
+/// * `vocab` - A reference to the `Vocabulary` containing the learned merge rules.
+///
+/// # Returns
+/// A `Vec<u32>` representing the encoded token sequence, or an error if unknown characters are encountered.
+pub fn encode_ordinary(input: &str, vocab: &Vocabulary) -> Result<Vec<u32>, EncodingError> {
+    let mut encoded = Vec::with_capacity(input.len());
+    encode_text(input, vocab, &mut encoded, &mut |tokens| {
+        merge_tokens(tokens, vocab)
+    })?;
+    Ok(encoded)
+}
 
-    for &token_id in &tokens {
-        if !vocab.id_to_token.contains_key(&token_id) {
+/// Splits `text` with the pre-tokenizer and appends the pieces merged with `merge` to `encoded`.
+fn encode_text(
+    text: &str,
+    vocab: &Vocabulary,
+    encoded: &mut Vec<u32>,
+    merge: &mut impl FnMut(Vec<u32>) -> Vec<u32>,
+) -> Result<(), EncodingError> {
+    for piece in vocab.pre_tokenizer().split(text) {
+        if vocab.alphabet() == Alphabet::Chars
+            && let Some(char) = piece.chars().find(|&char| vocab.char_id(char).is_none())
+        {
             return Err(EncodingError::CharNotInVocab {
-                char: char::from_u32(token_id)
-                    .map_or_else(|| "Invalid UTF-32".to_string(), |c| c.to_string()),
-                code: token_id,
+                char: char.to_string(),
+                code: char as u32,
             });
         }
+
+        encoded.extend(merge(vocab.symbols(piece)));
     }
+    Ok(())
+}
 
-    loop {
-        let mut best_pair: Option<(usize, Pair, u32)> = None; // (index, pair, merged_id)
+/// Encodes raw bytes into a sequence of token IDs using a pre-learned vocabulary.
+///
+/// A byte-level vocabulary accepts any input. Valid UTF-8 runs are encoded like [`encode`] does,
+/// while invalid sequences are merged as separate pieces. A character based vocabulary requires
+/// `input` to be valid UTF-8.
+///
+/// # Arguments
+/// * `input` - The bytes to encode.
+/// * `vocab` - A reference to the `Vocabulary` containing the learned merge rules.
+///
+/// # Returns
+/// A `Vec<u32>` representing the encoded token sequence, or an error if the input cannot be
+/// represented with the vocabulary.
+pub fn encode_bytes(input: &[u8], vocab: &Vocabulary) -> Result<Vec<u32>, EncodingError> {
+    encode_bytes_with_special(input, vocab, &AllowedSpecial::None)
+}
 
-        for i in 0..tokens.len().saturating_sub(1) {
-            let current_pair = Pair::new(tokens[i], tokens[i + 1]);
-            if let Some(&merged_id) = vocab.token_pair_to_id.get(&current_pair) {
-                if best_pair.is_none() || merged_id < best_pair.unwrap().2 {
-                    best_pair = Some((i, current_pair, merged_id));
+/// Encodes raw bytes, turning the literal text of allowed special tokens into their ids.
+///
+/// Works like [`encode_bytes`], with special tokens handled like [`encode_with_special`] does.
+///
+/// # Arguments
+/// * `input` - The bytes to encode.
+/// * `vocab` - A reference to the `Vocabulary` containing the learned merge rules.
+/// * `allowed` - The special tokens that may occur in the `input`.
+///
+/// # Returns
+/// A `Vec<u32>` representing the encoded token sequence, or an error if the input cannot be
+/// represented with the vocabulary.
+pub fn encode_bytes_with_special(
+    input: &[u8],
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+) -> Result<Vec<u32>, EncodingError> {
+    encode_bytes_segments(input, vocab, allowed, &mut |tokens| {
+        merge_tokens(tokens, vocab)
+    })
+}
+
+/// Encodes raw bytes with BPE-dropout, see [`encode_with_dropout`].
+///
+/// # Arguments
+/// * `input` - The bytes to encode.
+/// * `vocab` - A reference to the `Vocabulary` containing the learned merge rules.
+/// * `allowed` - The special tokens that may occur in the `input`.
+/// * `dropout` - The dropout probability and the state of its random number generator.
+///
+/// # Returns
+/// A `Vec<u32>` representing the encoded token sequence, or an error if the input cannot be
+/// represented with the vocabulary.
+pub fn encode_bytes_with_dropout(
+    input: &[u8],
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+    dropout: &mut Dropout,
+) -> Result<Vec<u32>, EncodingError> {
+    encode_bytes_segments(input, vocab, allowed, &mut |tokens| {
+        merge_tokens_with_dropout(tokens, vocab, dropout)
+    })
+}
+
+/// Encodes raw bytes, merging every piece with `merge`.
+pub(crate) fn encode_bytes_segments(
+    input: &[u8],
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+    merge: &mut impl FnMut(Vec<u32>) -> Vec<u32>,
+) -> Result<Vec<u32>, EncodingError> {
+    let mut encoded = Vec::with_capacity(input.len());
+    match vocab.alphabet() {
+        Alphabet::Chars => {
+            let input = std::str::from_utf8(input).map_err(|_| EncodingError::InvalidUtf8)?;
+            encode_segments(input, vocab, allowed, &mut encoded, merge)?;
+        }
+        Alphabet::Bytes => {
+            for chunk in input.utf8_chunks() {
+                encode_segments(chunk.valid(), vocab, allowed, &mut encoded, merge)?;
+                if !chunk.invalid().is_empty() {
+                    let tokens = chunk.invalid().iter().copied().map(u32::from).collect();
+                    encoded.extend(merge(tokens));
                 }
             }
         }
+    }
+    Ok(encoded)
+}
 
-        if best_pair.is_none() {
-            break;
+/// Applies the merge rules of the vocabulary to `tokens`, lowest merged id first.
+///
+/// Every occurrence of the pair with the lowest merged id is merged, from left to right, before
+/// the next pair is considered. Candidate merges are kept in a min-heap keyed by their merged id
+/// over a linked list of the symbols, so merging takes `O(n log n)` time.
+pub(crate) fn merge_tokens(tokens: Vec<u32>, vocab: &Vocabulary) -> Vec<u32> {
+    if tokens.len() < 2 || vocab.token_pair_to_id.is_empty() {
+        return tokens;
+    }
+
+    let mut symbols = tokens
+        .iter()
+        .enumerate()
+        .map(|(idx, &token)| Symbol {
+            token,
+            prev: if idx == 0 { NONE } else { idx - 1 },
+            next: if idx + 1 == tokens.len() {
+                NONE
+            } else {
+                idx + 1
+            },
+            removed: false,
+        })
+        .collect::<Vec<_>>();
+    let mut queue = MergeQueue::default();
+    for pos in 0..symbols.len() {
+        queue.push_candidate(&symbols, pos, vocab);
+    }
+
+    while let Some((merged_id, positions)) = queue.pop() {
+        let Some(Token::Pair(pair)) = vocab.id_to_token.get(&merged_id) else {
+            continue;
+        };
+        for pos in positions {
+            // positions are not removed when their symbols change, so check they still apply
+            let left = symbols[pos];
+            if left.removed
+                || left.token != pair.left
+                || left.next == NONE
+                || symbols[left.next].token != pair.right
+            {
+                continue;
+            }
+            let after = symbols[left.next].next;
+            symbols[left.next].removed = true;
+            symbols[pos].token = merged_id;
+            symbols[pos].next = after;
+            if after != NONE {
+                symbols[after].prev = pos;
+            }
+
+            // a merged token is newer than its parts, so these come after `merged_id`
+            if left.prev != NONE {
+                queue.push_candidate(&symbols, left.prev, vocab);
+            }
+            queue.push_candidate(&symbols, pos, vocab);
+        }
+    }
+
+    // the first symbol is always the left side of its merges, so it is never removed
+    let mut merged = Vec::new();
+    let mut pos = 0;
+    while pos != NONE {
+        merged.push(symbols[pos].token);
+        pos = symbols[pos].next;
+    }
+    merged
+}
+
+/// Marks a missing neighbour in the linked symbol list.
+const NONE: usize = usize::MAX;
+
+/// A symbol of the linked list [`merge_tokens`] works on.
+#[derive(Debug, Clone, Copy)]
+struct Symbol {
+    token: u32,
+    prev: usize,
+    next: usize,
+    removed: bool,
+}
+
+/// Positions of candidate merges, grouped by merged id.
+///
+/// Only the distinct merged ids go through the heap, which keeps it small and cache friendly on
+/// long inputs.
+#[derive(Default)]
+struct MergeQueue {
+    positions: HashMap<u32, Vec<usize>>,
+    merged_ids: BinaryHeap<Reverse<u32>>,
+}
+
+impl MergeQueue {
+    /// Adds the merge of the symbol at `pos` with the next one, if the vocabulary has it.
+    fn push_candidate(&mut self, symbols: &[Symbol], pos: usize, vocab: &Vocabulary) {
+        let next = symbols[pos].next;
+        if next == NONE {
+            return;
+        }
+        let pair = Pair::new(symbols[pos].token, symbols[next].token);
+        if let Some(&merged_id) = vocab.token_pair_to_id.get(&pair) {
+            self.push(merged_id, pos);
+        }
+    }
+
+    fn push(&mut self, merged_id: u32, pos: usize) {
+        let positions = self.positions.entry(merged_id).or_default();
+        if positions.is_empty() {
+            self.merged_ids.push(Reverse(merged_id));
+        }
+        positions.push(pos);
+    }
+
+    /// Pops the lowest merged id along with its positions, from left to right.
+    fn pop(&mut self) -> Option<(u32, Vec<usize>)> {
+        let Reverse(merged_id) = self.merged_ids.pop()?;
+        let mut positions = self.positions.remove(&merged_id).unwrap_or_default();
+        positions.sort_unstable();
+        Some((merged_id, positions))
+    }
+}
+
+/// Applies the merge rules of the vocabulary to `tokens` like [`merge_tokens`], skipping every
+/// applicable merge with the probability of `dropout`.
+///
+/// Merging stops once every applicable merge of a round has been skipped.
+fn merge_tokens_with_dropout(
+    mut tokens: Vec<u32>,
+    vocab: &Vocabulary,
+    dropout: &mut Dropout,
+) -> Vec<u32> {
+    loop {
+        // merged ids of the pairs starting at every index that survived dropout this round
+        let mut merges = vec![None; tokens.len()];
+        let mut best_id: Option<u32> = None;
+        for i in 0..tokens.len().saturating_sub(1) {
+            if let Some(&merged_id) = vocab
+                .token_pair_to_id
+                .get(&Pair::new(tokens[i], tokens[i + 1]))
+                && !dropout.drops()
+            {
+                merges[i] = Some(merged_id);
+                best_id = Some(best_id.map_or(merged_id, |best| best.min(merged_id)));
+            }
         }
 
-        let (_, pair_to_merge, merged_id) = best_pair.expect("cant be None");
+        let Some(merged_id) = best_id else {
+            break;
+        };
         let mut updated_tokens = Vec::with_capacity(tokens.len());
         let mut i = 0;
         while i < tokens.len() {
-            if i + 1 < tokens.len()
-                && tokens[i] == pair_to_merge.left
-                && tokens[i + 1] == pair_to_merge.right
-            {
+            if merges[i] == Some(merged_id) {
                 updated_tokens.push(merged_id);
                 i += 2;
             } else {
@@ -69,7 +397,7 @@ pub fn encode(input: &str, vocab: &Vocabulary) -> Result<Vec<u32>, EncodingError
         tokens = updated_tokens;
     }
 
-    Ok(tokens)
+    tokens
 }
 
 /// Decodes a sequence of token IDs back into a string using the vocabulary.
@@ -82,23 +410,91 @@ pub fn encode(input: &str, vocab: &Vocabulary) -> Result<Vec<u32>, EncodingError
 /// The decoded `String`, or an error if an unknown token ID is encountered or
 /// if a token ID cannot be represented as a valid character.
 pub fn decode(token_ids: &[u32], vocab: &Vocabulary) -> Result<String, EncodingError> {
-    let mut decoded_chars: Vec<char> = Vec::new();
+    decode_with(token_ids, vocab, false)
+}
+
+/// Decodes a sequence of token IDs back into a string, optionally leaving out special tokens.
+///
+/// # Arguments
+/// * `token_ids` - A slice of token IDs (`u32`) to decode.
+/// * `vocab` - A reference to the `Vocabulary` used for encoding.
+/// * `skip_special` - Whether special tokens are left out instead of rendered as their text.
+///
+/// # Returns
+/// The decoded `String`, or an error if an unknown token ID is encountered or
+/// if a token ID cannot be represented as a valid character.
+pub fn decode_with(
+    token_ids: &[u32],
+    vocab: &Vocabulary,
+    skip_special: bool,
+) -> Result<String, EncodingError> {
+    let decoded = decode_bytes_with(token_ids, vocab, skip_special)?;
+    String::from_utf8(decoded).map_err(|_| EncodingError::InvalidUtf8)
+}
+
+/// Decodes a sequence of token IDs back into raw bytes using the vocabulary.
+///
+/// Characters of a character based vocabulary are decoded as UTF-8. Unlike [`decode`], the
+/// output of a byte-level vocabulary does not have to be valid UTF-8.
+///
+/// # Arguments
+/// * `token_ids` - A slice of token IDs (`u32`) to decode.
+/// * `vocab` - A reference to the `Vocabulary` used for encoding.
+///
+/// # Returns
+/// The decoded bytes, or an error if an unknown token ID is encountered or
+/// if a token ID cannot be represented as a valid symbol.
+pub fn decode_bytes(token_ids: &[u32], vocab: &Vocabulary) -> Result<Vec<u8>, EncodingError> {
+    decode_bytes_with(token_ids, vocab, false)
+}
+
+/// Decodes a sequence of token IDs back into raw bytes, optionally leaving out special tokens.
+///
+/// # Arguments
+/// * `token_ids` - A slice of token IDs (`u32`) to decode.
+/// * `vocab` - A reference to the `Vocabulary` used for encoding.
+/// * `skip_special` - Whether special tokens are left out instead of rendered as their text.
+///
+/// # Returns
+/// The decoded bytes, or an error if an unknown token ID is encountered or
+/// if a token ID cannot be represented as a valid symbol.
+pub fn decode_bytes_with(
+    token_ids: &[u32],
+    vocab: &Vocabulary,
+    skip_special: bool,
+) -> Result<Vec<u8>, EncodingError> {
+    let mut decoded: Vec<u8> = Vec::new();
 
     for &id in token_ids {
         let mut decoding_stack: Vec<u32> = vec![id];
         while let Some(current_id) = decoding_stack.pop() {
             match vocab.id_to_token.get(&current_id) {
-                Some(Token::Lonely(lonely)) => match std::char::from_u32(lonely.0) {
-                    Some(c) => decoded_chars.push(c),
-                    None => {
-                        return Err(EncodingError::InvalidChar { code: lonely.0 });
-                    }
+                Some(Token::Lonely(lonely)) => match vocab.alphabet() {
+                    Alphabet::Chars => match std::char::from_u32(lonely.0) {
+                        Some(c) => decoded.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                        None => {
+                            return Err(EncodingError::InvalidChar { code: lonely.0 });
+                        }
+                    },
+                    Alphabet::Bytes => match u8::try_from(lonely.0) {
+                        Ok(byte) => decoded.push(byte),
+                        Err(_) => {
+                            return Err(EncodingError::InvalidChar { code: lonely.0 });
+                        }
+                    },
                 },
                 Some(Token::Pair(pair)) => {
                     // Push right then left, so left gets processed first (LIFO)
                     decoding_stack.push(pair.right);
                     decoding_stack.push(pair.left);
                 }
+                Some(Token::Special(_)) if skip_special => {}
+                Some(Token::Special(special)) => match vocab.special_token_text(special.0) {
+                    Some(text) => decoded.extend_from_slice(text.as_bytes()),
+                    None => {
+                        return Err(EncodingError::UnknownToken { code: special.0 });
+                    }
+                },
                 None => {
                     return Err(EncodingError::UnknownToken { code: current_id });
                 }
@@ -106,5 +502,5 @@ pub fn decode(token_ids: &[u32], vocab: &Vocabulary) -> Result<String, EncodingE
         }
     }
 
-    Ok(decoded_chars.into_iter().collect())
+    Ok(decoded)
 }
@@ -0,0 +1,223 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bincode::{Decode, Encode};
+use thiserror::Error;
+
+use crate::{TrainConfig, TrainObserver, TrainReport, Vocabulary, trainer::Trainer};
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Failed to access checkpoint: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to write checkpoint: {0}")]
+    Encode(#[from] bincode::error::EncodeError),
+    #[error("Failed to read checkpoint: {0}")]
+    Decode(#[from] bincode::error::DecodeError),
+}
+
+/// Where and how often checkpoints are saved while learning.
+///
+/// A checkpoint is saved as soon as either interval has passed since the previous one. Every
+/// checkpoint replaces the previous one at `path`.
+#[derive(Debug, Clone)]
+pub struct CheckpointConfig {
+    /// The file checkpoints are written to.
+    pub path: PathBuf,
+    /// Save a checkpoint after this many merges.
+    pub every_merges: Option<u32>,
+    /// Save a checkpoint after this much time.
+    pub every: Option<Duration>,
+}
+
+impl CheckpointConfig {
+    /// Creates a `CheckpointConfig` writing to `path` that never saves until an interval is set.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            every_merges: None,
+            every: None,
+        }
+    }
+}
+
+/// The saved state of a learning run: the partially learned vocabulary and the tokenized corpus.
+///
+/// Resuming a checkpoint gives the same vocabulary as a run that was never interrupted, provided
+/// it is resumed with the same [`TrainConfig`].
+#[derive(Debug, Encode, Decode)]
+pub struct Checkpoint {
+    vocab: Vocabulary,
+    segments: Vec<(Vec<u32>, u64)>,
+    n_merges: u32,
+}
+
+/// A borrowed [`Checkpoint`], so saving one does not copy the vocabulary.
+#[derive(Encode)]
+struct CheckpointRef<'a> {
+    vocab: &'a Vocabulary,
+    segments: Vec<(Vec<u32>, u64)>,
+    n_merges: u32,
+}
+
+impl Checkpoint {
+    /// Loads a checkpoint saved while learning.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or does not hold a checkpoint.
+    pub fn load(path: &Path) -> Result<Self, CheckpointError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let checkpoint = bincode::decode_from_std_read(&mut reader, bincode::config::standard())?;
+        Ok(checkpoint)
+    }
+
+    /// Returns the vocabulary learned up to the checkpoint.
+    pub fn vocabulary(&self) -> &Vocabulary {
+        &self.vocab
+    }
+
+    /// Returns the number of merges the run had performed at the checkpoint.
+    pub fn n_merges(&self) -> u32 {
+        self.n_merges
+    }
+
+    /// Continues the learning run from the checkpoint.
+    ///
+    /// # Arguments
+    /// * `config` - Parameters of the learning run, the same as the interrupted run used.
+    /// * `checkpoints` - Where and how often to keep saving checkpoints, if at all.
+    /// * `observer` - Called after every merge.
+    ///
+    /// # Returns
+    /// The learned vocabulary and a summary of the whole run, counting the merges performed
+    /// before the checkpoint.
+    ///
+    /// # Errors
+    /// Returns an error if saving a checkpoint fails.
+    pub fn resume(
+        self,
+        config: &TrainConfig,
+        checkpoints: Option<&CheckpointConfig>,
+        observer: &mut impl TrainObserver,
+    ) -> Result<(Vocabulary, TrainReport), CheckpointError> {
+        let Self {
+            mut vocab,
+            segments,
+            n_merges,
+        } = self;
+        let mut trainer = Trainer::new(segments, config.n_threads, config.pair_counting);
+        let mut checkpointer =
+            checkpoints.map(|checkpoints| Checkpointer::new(checkpoints, n_merges));
+        let report = vocab.perform_merges(
+            &mut trainer,
+            config,
+            n_merges,
+            observer,
+            checkpointer.as_mut(),
+        )?;
+        Ok((vocab, report))
+    }
+}
+
+/// Saves checkpoints of a learning run when they are due.
+pub(crate) struct Checkpointer<'a> {
+    config: &'a CheckpointConfig,
+    last_merges: u32,
+    last_time: Instant,
+}
+
+impl<'a> Checkpointer<'a> {
+    pub(crate) fn new(config: &'a CheckpointConfig, n_merges: u32) -> Self {
+        Self {
+            config,
+            last_merges: n_merges,
+            last_time: Instant::now(),
+        }
+    }
+
+    /// Saves a checkpoint if an interval has passed since the previous one.
+    pub(crate) fn save_if_due(
+        &mut self,
+        vocab: &Vocabulary,
+        trainer: &Trainer,
+        n_merges: u32,
+    ) -> Result<(), CheckpointError> {
+        let merges_due = self
+            .config
+            .every_merges
+            .is_some_and(|every| n_merges - self.last_merges >= every.max(1));
+        let time_due = self
+            .config
+            .every
+            .is_some_and(|every| self.last_time.elapsed() >= every);
+        if !merges_due && !time_due {
+            return Ok(());
+        }
+
+        let checkpoint = CheckpointRef {
+            vocab,
+            segments: trainer.segments(),
+            n_merges,
+        };
+        // write next to the previous checkpoint first, so a crash never leaves a torn file
+        let mut tmp_path = self.config.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        bincode::encode_into_std_write(&checkpoint, &mut writer, bincode::config::standard())?;
+        writer.flush()?;
+        fs::rename(&tmp_path, &self.config.path)?;
+
+        self.last_merges = n_merges;
+        self.last_time = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use super::*;
+    use crate::{Corpus, MergeEvent, PreTokenizer, StopReason};
+
+    #[test]
+    fn resumed_run_matches_uninterrupted_run() {
+        let mut corpus = Corpus::with_pre_tokenizer(PreTokenizer::Whitespace);
+        corpus.add_text("the cat sat on the mat, the rat sat on the hat and the bat ate that");
+        let config = TrainConfig::new(12);
+
+        let mut uninterrupted = Vocabulary::new();
+        let expected = uninterrupted.learn_corpus(&corpus, &config).unwrap();
+
+        let path = std::env::temp_dir().join(format!("bpers-checkpoint-{}", std::process::id()));
+        let checkpoints = CheckpointConfig {
+            every_merges: Some(3),
+            ..CheckpointConfig::new(&path)
+        };
+        // crash after the 5th merge, the last checkpoint holds 3 merges
+        let mut crashed = Vocabulary::new();
+        let report = crashed
+            .learn_corpus_checkpointed(&corpus, &config, &checkpoints, &mut |event: &MergeEvent| {
+                if event.index == 4 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+        assert_eq!(report.stop_reason, StopReason::Cancelled);
+
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.n_merges(), 3);
+        let (resumed, report) = checkpoint.resume(&config, None, &mut ()).unwrap();
+
+        assert_eq!(report, expected);
+        assert_eq!(resumed.id_to_token, uninterrupted.id_to_token);
+        assert_eq!(resumed.token_pair_to_id, uninterrupted.token_pair_to_id);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::MergeConstraint;
+
+/// Parameters of a vocabulary learning run.
+#[derive(Debug, Clone)]
+pub struct TrainConfig {
+    /// The max number of merges to perform.
+    pub n_merges: u32,
+    /// The vocabulary size to stop at, counting every token of the vocabulary.
+    ///
+    /// Learning stops as soon as either this size or `n_merges` is reached.
+    pub vocab_size: Option<usize>,
+    /// The minimum frequency of a pair to be merged.
+    pub min_frequency: u64,
+    /// The minimum compression gain of a merge.
+    ///
+    /// The gain is the frequency of the pair divided by the current length of the tokenized
+    /// corpus, i.e. the fraction of tokens the merge would remove. Learning stops before the first
+    /// merge whose gain is below this ratio.
+    pub min_gain: Option<f64>,
+    /// The number of worker threads used for pair counting and merging.
+    ///
+    /// The corpus is split into this many shards. Values below 2 train on the current thread.
+    /// The learned vocabulary does not depend on this setting.
+    pub n_threads: usize,
+    /// How occurrences of a pair are counted.
+    pub pair_counting: PairCounting,
+    /// The max number of base symbols, characters or bytes, a merged token may expand to.
+    ///
+    /// Pairs that would exceed it are never merged, except to build a forced token.
+    pub max_token_len: Option<usize>,
+    /// Rules forbidding some merges, see [`MergeConstraint`].
+    ///
+    /// They are added to the constraints of the vocabulary. Forced tokens are built regardless.
+    pub constraints: Vec<MergeConstraint>,
+    /// Texts that become single tokens, merged before any learned pair.
+    ///
+    /// Every text is broken down into a chain of merges. The merges count towards `n_merges` and
+    /// `vocab_size`, but are performed even if those limits are exceeded. Forced tokens take
+    /// precedence over `max_token_len`, `constraints` and `forbidden_merges` as well: their merges
+    /// are performed even if those rules forbid them. A text spanning several pieces of the
+    /// pre-tokenizer is never produced by encoding.
+    pub forced_tokens: Vec<String>,
+    /// Pairs of token texts that are never merged, as `(left, right)`, except to build a forced
+    /// token.
+    pub forbidden_merges: Vec<(String, String)>,
+}
+
+impl TrainConfig {
+    /// Creates a single-threaded `TrainConfig` performing up to `n_merges` merges.
+    pub fn new(n_merges: u32) -> Self {
+        Self {
+            n_merges,
+            vocab_size: None,
+            min_frequency: 2,
+            min_gain: None,
+            n_threads: 1,
+            pair_counting: PairCounting::Overlapping,
+            max_token_len: None,
+            constraints: Vec::new(),
+            forced_tokens: Vec::new(),
+            forbidden_merges: Vec::new(),
+        }
+    }
+
+    /// Creates a single-threaded `TrainConfig` merging until the vocabulary has `vocab_size`
+    /// tokens.
+    pub fn with_vocab_size(vocab_size: usize) -> Self {
+        Self {
+            vocab_size: Some(vocab_size),
+            ..Self::new(u32::MAX)
+        }
+    }
+}
+
+/// How occurrences of a pair are counted while learning.
+///
+/// The modes only differ for pairs of two equal tokens: `aaaa` holds three overlapping occurrences
+/// of `(a, a)`, but merging it replaces only two of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PairCounting {
+    /// Every pair of adjacent tokens counts, as vocabularies have always been learned.
+    #[default]
+    Overlapping,
+    /// Only the occurrences that a merge would replace count.
+    NonOverlapping,
+}
+
+/// Why a learning run stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The max number of merges was performed.
+    MaxMerges,
+    /// The vocabulary reached the target size.
+    VocabSizeReached,
+    /// No pair that may be merged occurs at least `min_frequency` times anymore.
+    NoFrequentPairs,
+    /// The compression gain of the next merge is below `min_gain`.
+    LowGain,
+    /// The observer of the run asked to stop.
+    Cancelled,
+}
+
+/// Summary of a learning run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrainReport {
+    /// The number of merges performed during the run.
+    pub n_merges: u32,
+    /// The size of the vocabulary after the run.
+    pub vocab_size: usize,
+    pub stop_reason: StopReason,
+}
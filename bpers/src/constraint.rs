@@ -0,0 +1,134 @@
+use bincode::{Decode, Encode};
+
+/// A coarse category of characters, used by [`MergeConstraint`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum CharCategory {
+    /// Alphabetic characters.
+    Letter,
+    /// Numeric characters.
+    Digit,
+    /// Whitespace characters.
+    Whitespace,
+    /// ASCII punctuation and symbols.
+    Punctuation,
+    /// Every other character, as well as the bytes of multi-byte characters in a byte-level
+    /// vocabulary.
+    Other,
+}
+
+impl CharCategory {
+    /// Returns the category of `char`.
+    pub fn of(char: char) -> Self {
+        if char.is_alphabetic() {
+            Self::Letter
+        } else if char.is_numeric() {
+            Self::Digit
+        } else if char.is_whitespace() {
+            Self::Whitespace
+        } else if char.is_ascii_punctuation() {
+            Self::Punctuation
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Returns the category of a byte of a byte-level vocabulary.
+    ///
+    /// Only ASCII bytes stand for a character of their own, so all others are [`Self::Other`].
+    pub fn of_byte(byte: u8) -> Self {
+        if byte.is_ascii() {
+            Self::of(char::from(byte))
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// The set of character categories a token expands to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CategorySet(u8);
+
+impl CategorySet {
+    /// Returns `true` if the set holds `category`.
+    pub fn contains(self, category: CharCategory) -> bool {
+        self.0 & Self::bit(category) != 0
+    }
+
+    /// Returns the set with `category` added.
+    pub fn with(self, category: CharCategory) -> Self {
+        Self(self.0 | Self::bit(category))
+    }
+
+    /// Returns the categories held by either set.
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    fn bit(category: CharCategory) -> u8 {
+        1 << category as u8
+    }
+}
+
+/// A rule forbidding some merges while learning.
+///
+/// Constraints are kept in the vocabulary they were learned into, see
+/// [`Vocabulary::constraints`](crate::Vocabulary::constraints).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum MergeConstraint {
+    /// No token holds characters of both categories, e.g. letters and whitespace.
+    Separate(CharCategory, CharCategory),
+    /// Characters of the category are never merged, e.g. digits are always split individually.
+    Isolate(CharCategory),
+}
+
+impl MergeConstraint {
+    /// Returns `true` if tokens with the categories `left` and `right` may be merged.
+    pub fn allows(&self, left: CategorySet, right: CategorySet) -> bool {
+        match *self {
+            Self::Separate(a, b) => {
+                let merged = left.union(right);
+                !(merged.contains(a) && merged.contains(b))
+            }
+            Self::Isolate(category) => !left.contains(category) && !right.contains(category),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Corpus, PreTokenizer, TrainConfig, Vocabulary};
+
+    #[test]
+    fn constraints_forbid_merges_and_are_kept() {
+        let mut corpus = Corpus::with_pre_tokenizer(PreTokenizer::None);
+        for _ in 0..5 {
+            corpus.add_text("pay 100 or 200 today, ok? pay 100 today");
+        }
+        let config = TrainConfig {
+            constraints: vec![
+                MergeConstraint::Separate(CharCategory::Letter, CharCategory::Whitespace),
+                MergeConstraint::Isolate(CharCategory::Digit),
+                MergeConstraint::Isolate(CharCategory::Punctuation),
+            ],
+            ..TrainConfig::new(100)
+        };
+
+        let mut vocabulary = Vocabulary::new();
+        vocabulary.learn_corpus(&corpus, &config).unwrap();
+        assert!(!vocabulary.merges().is_empty());
+        for stats in vocabulary.merges() {
+            let categories = vocabulary.token_categories(stats.id).unwrap();
+            assert!(!categories.contains(CharCategory::Digit));
+            assert!(!categories.contains(CharCategory::Punctuation));
+            assert!(
+                !(categories.contains(CharCategory::Letter)
+                    && categories.contains(CharCategory::Whitespace))
+            );
+        }
+        assert_eq!(vocabulary.constraints(), config.constraints);
+
+        let encoded = crate::encode("100", &vocabulary).unwrap();
+        assert_eq!(encoded.len(), 3);
+    }
+}
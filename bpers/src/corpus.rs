@@ -0,0 +1,292 @@
+use std::io::{self, BufRead};
+
+use indexmap::IndexMap;
+
+use crate::{
+    PreTokenizer, Vocabulary,
+    special::{Segment, split_specials},
+    stream::safe_text_boundary,
+};
+
+/// The size a whole-file document grows to before its finished chunks are added.
+const BLOCK_SIZE: usize = 1 << 20;
+
+pub(crate) type FoldIndexMap<K, V> = IndexMap<K, V, foldhash::fast::FixedState>;
+
+/// How text read into a [`Corpus`] is split into documents. No chunk ever spans two documents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DocumentSplit {
+    /// Everything read from a reader is a single document.
+    ///
+    /// The document is added in blocks cut where no chunk can span, so only its unfinished tail is
+    /// held in memory. Without a pre-tokenizer, the whole document is a single chunk and held in
+    /// memory as a whole.
+    #[default]
+    File,
+    /// Every line, including its terminator, is a document.
+    Line,
+    /// Every run of non-blank lines is a document. Blank lines are documents of their own.
+    Paragraph,
+}
+
+/// A text corpus aggregated into distinct chunks along with their counts.
+///
+/// Text added to the corpus is split into chunks by its [`PreTokenizer`]. Chunks keep the order in
+/// which they were first seen, so learning from a `Corpus` is deterministic. The literal text of
+/// special tokens is cut out of added text before splitting and never becomes part of a chunk.
+#[derive(Debug, Default, Clone)]
+pub struct Corpus {
+    pre_tokenizer: PreTokenizer,
+    special_tokens: Vec<String>,
+    chunks: FoldIndexMap<String, u64>,
+}
+
+impl Corpus {
+    /// Creates an empty `Corpus`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty `Corpus` splitting added text with `pre_tokenizer`.
+    pub fn with_pre_tokenizer(pre_tokenizer: PreTokenizer) -> Self {
+        Self {
+            pre_tokenizer,
+            ..Self::default()
+        }
+    }
+
+    /// Creates an empty `Corpus` splitting added text with `pre_tokenizer` and cutting out the
+    /// literal text of `special_tokens`.
+    pub fn with_special_tokens<S: Into<String>>(
+        pre_tokenizer: PreTokenizer,
+        special_tokens: impl IntoIterator<Item = S>,
+    ) -> Self {
+        Self {
+            pre_tokenizer,
+            special_tokens: special_tokens.into_iter().map(Into::into).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Creates an empty `Corpus` splitting added text like `vocab` does when encoding.
+    ///
+    /// The corpus takes over the pre-tokenizer and the special tokens of the vocabulary.
+    pub fn for_vocabulary(vocab: &Vocabulary) -> Self {
+        Self::with_special_tokens(
+            vocab.pre_tokenizer().clone(),
+            vocab.special_tokens().map(|(text, _)| text),
+        )
+    }
+
+    /// Returns the pre-tokenizer used to split added text.
+    pub fn pre_tokenizer(&self) -> &PreTokenizer {
+        &self.pre_tokenizer
+    }
+
+    /// Returns the texts of the special tokens cut out of added text.
+    pub fn special_tokens(&self) -> impl Iterator<Item = &str> {
+        self.special_tokens.iter().map(String::as_str)
+    }
+
+    /// Splits `text` with the pre-tokenizer and adds every piece to the corpus.
+    ///
+    /// Pieces never span a special token, and special tokens themselves are left out.
+    pub fn add_text(&mut self, text: &str) {
+        for segment in split_specials(text, self.special_tokens.iter().map(String::as_str)) {
+            let Segment::Text(text) = segment else {
+                continue;
+            };
+            for piece in self.pre_tokenizer.split(text) {
+                self.add(piece);
+            }
+        }
+    }
+
+    /// Adds a single occurrence of `chunk` to the corpus.
+    pub fn add(&mut self, chunk: &str) {
+        self.add_count(chunk, 1);
+    }
+
+    /// Adds `count` occurrences of `chunk` to the corpus.
+    pub fn add_count(&mut self, chunk: &str, count: u64) {
+        if chunk.is_empty() || count == 0 {
+            return;
+        }
+        match self.chunks.get_mut(chunk) {
+            Some(total) => *total += count,
+            None => {
+                self.chunks.insert(chunk.to_string(), count);
+            }
+        }
+    }
+
+    /// Streams `reader` into the corpus line by line.
+    ///
+    /// Every line, including its terminator, is added with [`Corpus::add_text`], so chunks never
+    /// span two lines.
+    ///
+    /// # Errors
+    /// Returns an error if reading fails or the input is not valid UTF-8.
+    pub fn read_from<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+        self.read_documents_from(reader, DocumentSplit::Line)
+    }
+
+    /// Reads `reader` into the corpus, adding every document with [`Corpus::add_text`].
+    ///
+    /// Only one line or paragraph is held in memory at a time. Whole-file documents are added in
+    /// blocks, see [`DocumentSplit::File`].
+    ///
+    /// # Errors
+    /// Returns an error if reading fails or the input is not valid UTF-8.
+    pub fn read_documents_from<R: BufRead>(
+        &mut self,
+        mut reader: R,
+        split: DocumentSplit,
+    ) -> io::Result<()> {
+        let mut document = String::new();
+        match split {
+            DocumentSplit::File => {
+                let max_special_len = self.special_tokens.iter().map(String::len).max();
+                let mut next_block = BLOCK_SIZE;
+                let mut line = String::new();
+                while reader.read_line(&mut line)? > 0 {
+                    document.push_str(&line);
+                    line.clear();
+                    if document.len() < next_block {
+                        continue;
+                    }
+
+                    let specials = self.special_tokens.iter().map(String::as_str);
+                    let boundary = safe_text_boundary(
+                        &document,
+                        split_specials(&document, specials),
+                        max_special_len.unwrap_or(0),
+                        &self.pre_tokenizer,
+                        |_, _| false,
+                    );
+                    self.add_text(&document[..boundary]);
+                    document.drain(..boundary);
+                    // wait for the tail to double before trying again if nothing could be added
+                    next_block = BLOCK_SIZE.max(document.len() * 2);
+                }
+                self.add_text(&document);
+            }
+            DocumentSplit::Line => {
+                while reader.read_line(&mut document)? > 0 {
+                    self.add_text(&document);
+                    document.clear();
+                }
+            }
+            DocumentSplit::Paragraph => {
+                let mut line = String::new();
+                while reader.read_line(&mut line)? > 0 {
+                    if line.trim().is_empty() {
+                        self.add_text(&document);
+                        self.add_text(&line);
+                        document.clear();
+                    } else {
+                        document.push_str(&line);
+                    }
+                    line.clear();
+                }
+                self.add_text(&document);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of distinct chunks.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Returns `true` if the corpus has no chunks.
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Returns the position of `chunk` in the corpus.
+    pub(crate) fn index_of(&self, chunk: &str) -> Option<usize> {
+        self.chunks.get_index_of(chunk)
+    }
+
+    /// Iterates over distinct chunks and their counts, in the order they were first seen.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.chunks
+            .iter()
+            .map(|(chunk, &count)| (chunk.as_str(), count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_from_aggregates_lines() {
+        let mut corpus = Corpus::new();
+        corpus.read_from("foo\nbar\nfoo\n".as_bytes()).unwrap();
+        corpus.read_from("bar\nfoo".as_bytes()).unwrap();
+
+        let chunks = corpus.iter().collect::<Vec<_>>();
+        assert_eq!(chunks, [("foo\n", 2), ("bar\n", 2), ("foo", 1)]);
+    }
+
+    #[test]
+    fn read_documents_from_splits_paragraphs() {
+        let text = "a\nb\n\nc\nd\n\n\na\nb\n";
+
+        let mut corpus = Corpus::new();
+        corpus
+            .read_documents_from(text.as_bytes(), DocumentSplit::Paragraph)
+            .unwrap();
+        let chunks = corpus.iter().collect::<Vec<_>>();
+        assert_eq!(chunks, [("a\nb\n", 2), ("\n", 3), ("c\nd\n", 1)]);
+
+        let mut corpus = Corpus::new();
+        corpus
+            .read_documents_from(text.as_bytes(), DocumentSplit::File)
+            .unwrap();
+        let chunks = corpus.iter().collect::<Vec<_>>();
+        assert_eq!(chunks, [(text, 1)]);
+    }
+
+    #[test]
+    fn add_text_counts_pieces() {
+        let mut corpus = Corpus::with_pre_tokenizer(PreTokenizer::Whitespace);
+        corpus.add_text("to be or not to be");
+
+        let chunks = corpus.iter().collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            [("to", 2), (" ", 5), ("be", 2), ("or", 1), ("not", 1)]
+        );
+    }
+
+    #[test]
+    fn add_text_skips_special_tokens() {
+        let mut vocab = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        vocab.add_special_token("<|endoftext|>");
+        let mut corpus = Corpus::for_vocabulary(&vocab);
+        corpus.add_text("one<|endoftext|>two one<|endoftext|>");
+
+        let chunks = corpus.iter().collect::<Vec<_>>();
+        assert_eq!(chunks, [("one", 2), ("two", 1), (" ", 1)]);
+    }
+
+    #[test]
+    fn whole_files_are_added_in_blocks() {
+        let text = (0..40_000)
+            .map(|i| format!("line {i}:  it's <|sep|>x{}  \n", i % 7))
+            .collect::<String>();
+        assert!(text.len() > BLOCK_SIZE);
+
+        let mut expected = Corpus::with_special_tokens(PreTokenizer::Whitespace, ["<|sep|>"]);
+        expected.add_text(&text);
+        let mut corpus = Corpus::with_special_tokens(PreTokenizer::Whitespace, ["<|sep|>"]);
+        corpus
+            .read_documents_from(text.as_bytes(), DocumentSplit::File)
+            .unwrap();
+        assert!(corpus.iter().eq(expected.iter()));
+    }
+}
@@ -0,0 +1,87 @@
+/// The BPE-dropout probability along with a seeded random number generator.
+///
+/// See [`encode_with_dropout`](crate::encode_with_dropout). The generator is advanced by every
+/// encoding, so encoding several texts with one `Dropout` gives different but reproducible
+/// results.
+#[derive(Debug, Clone)]
+pub struct Dropout {
+    probability: f64,
+    state: u64,
+}
+
+impl Dropout {
+    /// Creates a `Dropout` skipping merges with the given `probability`.
+    ///
+    /// # Panics
+    /// Panics if `probability` is not within `0.0..=1.0`.
+    pub fn new(probability: f64, seed: u64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "dropout probability must be within 0 and 1"
+        );
+        Self {
+            probability,
+            state: seed,
+        }
+    }
+
+    /// Returns the probability of skipping a merge.
+    pub fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    /// Decides whether the next merge is skipped.
+    pub(crate) fn drops(&mut self) -> bool {
+        // do not advance the generator when the outcome is certain
+        match self.probability {
+            0.0 => false,
+            1.0 => true,
+            probability => self.next_f64() < probability,
+        }
+    }
+
+    /// Returns a number within `0.0..1.0`, using SplitMix64.
+    fn next_f64(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllowedSpecial, Vocabulary, decode, encode, encode_with_dropout};
+
+    #[test]
+    fn dropout_is_reproducible_and_decodable() {
+        let input = "the cat sat on the mat with the other cat";
+        let mut vocab = Vocabulary::new();
+        vocab.learn(input, 20);
+        let allowed = AllowedSpecial::None;
+
+        let mut no_dropout = Dropout::new(0.0, 7);
+        let encoded = encode_with_dropout(input, &vocab, &allowed, &mut no_dropout).unwrap();
+        assert_eq!(encoded, encode(input, &vocab).unwrap());
+
+        let mut full_dropout = Dropout::new(1.0, 7);
+        let encoded = encode_with_dropout(input, &vocab, &allowed, &mut full_dropout).unwrap();
+        assert_eq!(encoded.len(), input.chars().count());
+
+        let first = encode_with_dropout(input, &vocab, &allowed, &mut Dropout::new(0.3, 42));
+        let second = encode_with_dropout(input, &vocab, &allowed, &mut Dropout::new(0.3, 42));
+        let first = first.unwrap();
+        assert_eq!(first, second.unwrap());
+        assert_eq!(decode(&first, &vocab).unwrap(), input);
+
+        let encodings = (0..20)
+            .map(|seed| {
+                encode_with_dropout(input, &vocab, &allowed, &mut Dropout::new(0.3, seed)).unwrap()
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert!(encodings.len() > 1);
+    }
+}
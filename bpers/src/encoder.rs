@@ -0,0 +1,187 @@
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use lru::LruCache;
+
+use crate::{
+    AllowedSpecial, EncodingError, Vocabulary,
+    bpe::{encode_bytes_segments, encode_segments, merge_tokens},
+};
+
+/// The number of chunks an [`Encoder`] remembers by default.
+const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// Hit and miss counts of the cache of an [`Encoder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of chunks whose encoding was found in the cache.
+    pub hits: u64,
+    /// The number of chunks that had to be merged.
+    pub misses: u64,
+    /// The number of chunks in the cache.
+    pub len: usize,
+    /// The max number of chunks in the cache.
+    pub capacity: usize,
+}
+
+/// Encodes text with a vocabulary, remembering the encoding of every pre-tokenized chunk.
+///
+/// Chunks are cached by their base symbols in a bounded LRU cache, so each distinct word is
+/// merged only once as long as it stays in the cache. The output is identical to the one of
+/// [`encode`](crate::encode) and friends.
+///
+/// The encoder can be shared between threads. Merging happens outside of the lock, so threads
+/// only wait on each other for cache lookups.
+#[derive(Debug)]
+pub struct Encoder<'a> {
+    vocab: &'a Vocabulary,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Debug)]
+struct Cache {
+    entries: LruCache<Vec<u32>, Vec<u32>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<'a> Encoder<'a> {
+    /// Creates an `Encoder` caching up to 10,000 chunks.
+    pub fn new(vocab: &'a Vocabulary) -> Self {
+        Self::with_capacity(vocab, DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Creates an `Encoder` caching up to `capacity` chunks, at least one.
+    pub fn with_capacity(vocab: &'a Vocabulary, capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            vocab,
+            cache: Mutex::new(Cache {
+                entries: LruCache::new(capacity),
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// Returns the vocabulary the encoder encodes with.
+    pub fn vocabulary(&self) -> &'a Vocabulary {
+        self.vocab
+    }
+
+    /// Encodes an input string, see [`encode`](crate::encode).
+    ///
+    /// # Errors
+    /// Returns an error if unknown characters or special tokens are encountered.
+    pub fn encode(&self, input: &str) -> Result<Vec<u32>, EncodingError> {
+        self.encode_with_special(input, &AllowedSpecial::None)
+    }
+
+    /// Encodes an input string, turning the literal text of allowed special tokens into their
+    /// ids, see [`encode_with_special`](crate::encode_with_special).
+    ///
+    /// # Errors
+    /// Returns an error if unknown characters or special tokens that are not allowed are
+    /// encountered.
+    pub fn encode_with_special(
+        &self,
+        input: &str,
+        allowed: &AllowedSpecial,
+    ) -> Result<Vec<u32>, EncodingError> {
+        let mut encoded = Vec::with_capacity(input.len());
+        encode_segments(input, self.vocab, allowed, &mut encoded, &mut |tokens| {
+            self.merge(tokens)
+        })?;
+        Ok(encoded)
+    }
+
+    /// Encodes raw bytes, turning the literal text of allowed special tokens into their ids, see
+    /// [`encode_bytes_with_special`](crate::encode_bytes_with_special).
+    ///
+    /// # Errors
+    /// Returns an error if the input cannot be represented with the vocabulary.
+    pub fn encode_bytes_with_special(
+        &self,
+        input: &[u8],
+        allowed: &AllowedSpecial,
+    ) -> Result<Vec<u32>, EncodingError> {
+        encode_bytes_segments(input, self.vocab, allowed, &mut |tokens| self.merge(tokens))
+    }
+
+    /// Returns the hit and miss counts of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.lock();
+        CacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            len: cache.entries.len(),
+            capacity: cache.entries.cap().get(),
+        }
+    }
+
+    /// Empties the cache and resets its statistics.
+    pub fn clear(&self) {
+        let mut cache = self.lock();
+        cache.entries.clear();
+        cache.hits = 0;
+        cache.misses = 0;
+    }
+
+    /// Merges the base symbols of a chunk, looking them up in the cache first.
+    fn merge(&self, tokens: Vec<u32>) -> Vec<u32> {
+        {
+            let mut cache = self.lock();
+            if let Some(merged) = cache.entries.get(&tokens).cloned() {
+                cache.hits += 1;
+                return merged;
+            }
+            cache.misses += 1;
+        }
+
+        let merged = merge_tokens(tokens.clone(), self.vocab);
+        self.lock().entries.put(tokens, merged.clone());
+        merged
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Cache> {
+        // the cache is never left half updated, so it is fine to use after a panic
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::PreTokenizer;
+
+    #[test]
+    fn cached_encoding_matches_encode() {
+        let text = "the cat sat on the mat and the cat ate the rat";
+        let mut vocab = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        vocab.learn(text, 20);
+        let expected = crate::encode(text, &vocab).unwrap();
+
+        let encoder = Encoder::new(&vocab);
+        assert_eq!(encoder.encode(text).unwrap(), expected);
+        let stats = encoder.stats();
+        // 23 chunks: 8 distinct words and a space
+        assert_eq!((stats.misses, stats.len), (9, 9));
+        assert_eq!(stats.hits, 23 - 9);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| assert_eq!(encoder.encode(text).unwrap(), expected));
+            }
+        });
+        assert_eq!(encoder.stats().misses, 9);
+
+        let small = Encoder::with_capacity(&vocab, 2);
+        assert_eq!(small.encode(text).unwrap(), expected);
+        assert_eq!(small.stats().len, 2);
+        small.clear();
+        assert_eq!(small.stats().hits, 0);
+    }
+}
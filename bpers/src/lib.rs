@@ -1,7 +1,38 @@
+mod batch;
 mod bpe;
+mod checkpoint;
+mod config;
+mod constraint;
+mod corpus;
+mod dropout;
+mod encoder;
+mod model;
+mod observer;
+mod offsets;
+mod pre_tokenizer;
+mod special;
+mod stream;
 mod token_pair;
+mod trainer;
+mod unigram;
 mod vocabulary;
+mod wordpiece;
 
+pub use batch::*;
 pub use bpe::*;
+pub use checkpoint::*;
+pub use config::*;
+pub use constraint::*;
+pub use corpus::*;
+pub use dropout::*;
+pub use encoder::*;
+pub use model::*;
+pub use observer::*;
+pub use offsets::*;
+pub use pre_tokenizer::*;
+pub use special::*;
+pub use stream::*;
 pub use token_pair::*;
+pub use unigram::*;
 pub use vocabulary::*;
+pub use wordpiece::*;
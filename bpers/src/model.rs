@@ -0,0 +1,255 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bincode::{Decode, Encode};
+use thiserror::Error;
+
+use crate::{
+    AllowedSpecial, Encoder, EncodingError, Unigram, Vocabulary, WordPiece, batch::map_parallel,
+    decode_bytes_with, encode_bytes_with_special,
+};
+
+/// The bytes every saved model starts with, followed by the version of its format.
+const MAGIC: &[u8] = b"BPERS\0";
+/// The version of the format models are saved in. Bumped whenever the saved layout changes, so
+/// files of earlier versions can still be decoded.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum ModelError {
+    #[error("Failed to access model file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to write model: {0}")]
+    Encode(#[from] bincode::error::EncodeError),
+    #[error("Failed to read model: {0}")]
+    Decode(#[from] bincode::error::DecodeError),
+    #[error("Unsupported model format version {0}")]
+    UnsupportedVersion(u32),
+}
+
+/// A learned tokenizer of any of the supported kinds.
+#[derive(Debug, Encode, Decode)]
+pub enum Model {
+    /// A byte pair encoding vocabulary.
+    Bpe(Vocabulary),
+    /// A Unigram language model.
+    Unigram(Unigram),
+    /// A WordPiece model.
+    WordPiece(WordPiece),
+}
+
+impl Model {
+    /// Saves the model to `path`, headed by the version of the format.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be written.
+    pub fn save(&self, path: &Path) -> Result<(), ModelError> {
+        let config = bincode::config::standard();
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        bincode::encode_into_std_write(FORMAT_VERSION, &mut writer, config)?;
+        bincode::encode_into_std_write(self, &mut writer, config)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Loads a model saved with [`Model::save`].
+    ///
+    /// Files without a version header are BPE vocabularies saved before the format was
+    /// versioned, see [`Vocabulary::decode_legacy`].
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read, does not hold a model, or was saved in a
+    /// later version of the format.
+    pub fn load(path: &Path) -> Result<Self, ModelError> {
+        Self::decode(&fs::read(path)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, ModelError> {
+        let config = bincode::config::standard();
+        let Some(bytes) = bytes.strip_prefix(MAGIC) else {
+            return Ok(Self::Bpe(Vocabulary::decode_legacy(bytes)?));
+        };
+        let (version, read): (u32, _) = bincode::decode_from_slice(bytes, config)?;
+        match version {
+            FORMAT_VERSION => Ok(bincode::decode_from_slice(&bytes[read..], config)?.0),
+            version => Err(ModelError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Returns the number of tokens of the model.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Bpe(vocab) => vocab.id_to_token.len(),
+            Self::Unigram(unigram) => unigram.len(),
+            Self::WordPiece(wordpiece) => wordpiece.len(),
+        }
+    }
+
+    /// Returns `true` if the model has no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Encodes `input` into a sequence of token ids.
+    ///
+    /// # Errors
+    /// Returns an error if the input cannot be encoded by the model.
+    pub fn encode_with_special(
+        &self,
+        input: &str,
+        allowed: &AllowedSpecial,
+    ) -> Result<Vec<u32>, EncodingError> {
+        match self {
+            Self::Bpe(vocab) => crate::encode_with_special(input, vocab, allowed),
+            Self::Unigram(unigram) => unigram.encode_with_special(input, allowed),
+            Self::WordPiece(wordpiece) => wordpiece.encode_with_special(input, allowed),
+        }
+    }
+
+    /// Encodes raw bytes into a sequence of token ids.
+    ///
+    /// Only byte-level vocabularies accept input that is not valid UTF-8.
+    ///
+    /// # Errors
+    /// Returns an error if the input cannot be encoded by the model.
+    pub fn encode_bytes_with_special(
+        &self,
+        input: &[u8],
+        allowed: &AllowedSpecial,
+    ) -> Result<Vec<u32>, EncodingError> {
+        match self {
+            Self::Bpe(vocab) => encode_bytes_with_special(input, vocab, allowed),
+            Self::Unigram(unigram) => {
+                let input = std::str::from_utf8(input).map_err(|_| EncodingError::InvalidUtf8)?;
+                unigram.encode_with_special(input, allowed)
+            }
+            Self::WordPiece(wordpiece) => {
+                let input = std::str::from_utf8(input).map_err(|_| EncodingError::InvalidUtf8)?;
+                wordpiece.encode_with_special(input, allowed)
+            }
+        }
+    }
+
+    /// Encodes a batch of raw byte inputs in parallel, see
+    /// [`encode_batch`](crate::encode_batch).
+    ///
+    /// # Returns
+    /// The encoding of every input, in input order. A document that fails to encode does not
+    /// affect the others.
+    pub fn encode_bytes_batch_with_special<B>(
+        &self,
+        inputs: &[B],
+        allowed: &AllowedSpecial,
+    ) -> Vec<Result<Vec<u32>, EncodingError>>
+    where
+        B: AsRef<[u8]> + Sync,
+    {
+        match self {
+            Self::Bpe(vocab) => {
+                let encoder = Encoder::new(vocab);
+                map_parallel(inputs, |input| {
+                    encoder.encode_bytes_with_special(input.as_ref(), allowed)
+                })
+            }
+            _ => map_parallel(inputs, |input| {
+                self.encode_bytes_with_special(input.as_ref(), allowed)
+            }),
+        }
+    }
+
+    /// Decodes a sequence of token ids back into raw bytes, optionally leaving out special tokens.
+    ///
+    /// # Errors
+    /// Returns an error if an unknown token id is encountered.
+    pub fn decode_bytes_with(
+        &self,
+        token_ids: &[u32],
+        skip_special: bool,
+    ) -> Result<Vec<u8>, EncodingError> {
+        match self {
+            Self::Bpe(vocab) => decode_bytes_with(token_ids, vocab, skip_special),
+            Self::Unigram(unigram) => unigram
+                .decode_with(token_ids, skip_special)
+                .map(String::into_bytes),
+            Self::WordPiece(wordpiece) => wordpiece
+                .decode_with(token_ids, skip_special)
+                .map(String::into_bytes),
+        }
+    }
+
+    /// Decodes a batch of token id sequences back into raw bytes in parallel, optionally leaving
+    /// out special tokens.
+    ///
+    /// # Returns
+    /// The decoded bytes of every document, in input order. A document that fails to decode does
+    /// not affect the others.
+    pub fn decode_bytes_batch_with<T>(
+        &self,
+        token_ids: &[T],
+        skip_special: bool,
+    ) -> Vec<Result<Vec<u8>, EncodingError>>
+    where
+        T: AsRef<[u32]> + Sync,
+    {
+        map_parallel(token_ids, |ids| {
+            self.decode_bytes_with(ids.as_ref(), skip_special)
+        })
+    }
+}
+
+impl From<Vocabulary> for Model {
+    fn from(vocab: Vocabulary) -> Self {
+        Self::Bpe(vocab)
+    }
+}
+
+impl From<Unigram> for Model {
+    fn from(unigram: Unigram) -> Self {
+        Self::Unigram(unigram)
+    }
+}
+
+impl From<WordPiece> for Model {
+    fn from(wordpiece: WordPiece) -> Self {
+        Self::WordPiece(wordpiece)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PreTokenizer;
+
+    #[test]
+    fn loads_saved_and_unversioned_models() {
+        let mut vocab = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        vocab.learn("the cat sat on the mat", 5);
+        vocab.add_special_token("<eos>");
+        let path = std::env::temp_dir().join(format!("bpers-model-{}", std::process::id()));
+        Model::Bpe(vocab).save(&path).unwrap();
+        let loaded = Model::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let Model::Bpe(loaded) = loaded else {
+            panic!("a BPE vocabulary was saved");
+        };
+        assert_eq!(loaded.pre_tokenizer(), &PreTokenizer::Whitespace);
+        assert!(loaded.special_token_id("<eos>").is_some());
+
+        // an empty vocabulary saved before the format was versioned
+        let Model::Bpe(legacy) = Model::decode(&[0x00, 0x00, 0x01]).unwrap() else {
+            panic!("unversioned files hold BPE vocabularies");
+        };
+        assert!(legacy.id_to_token.is_empty());
+
+        let mut future = MAGIC.to_vec();
+        future.push(2);
+        assert!(matches!(
+            Model::decode(&future),
+            Err(ModelError::UnsupportedVersion(2))
+        ));
+    }
+}
@@ -0,0 +1,40 @@
+use std::ops::ControlFlow;
+
+use crate::Pair;
+
+/// A merge performed while learning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeEvent {
+    /// The zero-based index of the merge within the learning run.
+    pub index: u32,
+    /// The merged pair.
+    pub pair: Pair,
+    /// The id of the new token.
+    pub id: u32,
+    /// The frequency of the pair at the time it was chosen.
+    pub frequency: u64,
+    /// The length of the tokenized corpus after the merge.
+    pub sequence_len: u64,
+}
+
+/// Watches a learning run, see [`Vocabulary::learn_corpus_observed`](crate::Vocabulary::learn_corpus_observed).
+pub trait TrainObserver {
+    /// Called after every merge.
+    ///
+    /// Returning [`ControlFlow::Break`] stops learning right away. The merge described by `event`
+    /// is kept, so the vocabulary stays valid.
+    fn on_merge(&mut self, event: &MergeEvent) -> ControlFlow<()>;
+}
+
+/// Observes nothing and never stops learning.
+impl TrainObserver for () {
+    fn on_merge(&mut self, _event: &MergeEvent) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+impl<F: FnMut(&MergeEvent) -> ControlFlow<()>> TrainObserver for F {
+    fn on_merge(&mut self, event: &MergeEvent) -> ControlFlow<()> {
+        self(event)
+    }
+}
@@ -0,0 +1,109 @@
+use std::ops::Range;
+
+use foldhash::HashMap;
+
+use crate::{AllowedSpecial, EncodingError, Vocabulary, decode_bytes, encode_with_special};
+
+/// A token id along with the part of the input it covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenSpan {
+    /// The id of the token.
+    pub id: u32,
+    /// The byte offsets of the token in the input.
+    pub bytes: Range<usize>,
+    /// The char offsets of the token in the input.
+    ///
+    /// A token of a byte-level vocabulary may cover only some bytes of a character. Its range
+    /// then includes the whole character, so it overlaps the range of the neighbouring token.
+    pub chars: Range<usize>,
+}
+
+/// Encodes an input string like [`encode_with_special`], along with the part of the input every
+/// token covers.
+///
+/// Encoding is lossless, so the spans of the tokens are adjacent and cover the whole input. The
+/// span of a special token is its literal text.
+///
+/// # Arguments
+/// * `input` - The string to encode.
+/// * `vocab` - A reference to the `Vocabulary` containing the learned merge rules.
+/// * `allowed` - The special tokens that may occur in the `input`.
+///
+/// # Returns
+/// A `TokenSpan` for every token in order, or an error if unknown characters or special tokens
+/// that are not allowed are encountered.
+pub fn encode_with_offsets(
+    input: &str,
+    vocab: &Vocabulary,
+    allowed: &AllowedSpecial,
+) -> Result<Vec<TokenSpan>, EncodingError> {
+    let token_ids = encode_with_special(input, vocab, allowed)?;
+    let char_starts = input.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+    // the char at a byte offset, or the one containing it
+    let char_at = |offset: usize| char_starts.partition_point(|&start| start <= offset) - 1;
+    // the first char starting at or after a byte offset
+    let char_after = |offset: usize| char_starts.partition_point(|&start| start < offset);
+
+    let mut token_lens = HashMap::default();
+    let mut spans = Vec::with_capacity(token_ids.len());
+    let mut start = 0;
+    for id in token_ids {
+        let len = match token_lens.get(&id) {
+            Some(&len) => len,
+            None => {
+                let len = decode_bytes(&[id], vocab)?.len();
+                token_lens.insert(id, len);
+                len
+            }
+        };
+        let end = start + len;
+        spans.push(TokenSpan {
+            id,
+            bytes: start..end,
+            chars: char_at(start)..char_after(end),
+        });
+        start = end;
+    }
+    debug_assert_eq!(start, input.len(), "tokens cover the whole input");
+    Ok(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PreTokenizer, decode};
+
+    #[test]
+    fn spans_cover_the_tokens() {
+        let text = "naïve café, naïve thé";
+        let mut vocab = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        vocab.learn(text, 10);
+        vocab.add_special_token("<|sep|>");
+
+        let input = "café<|sep|>naïve";
+        let spans = encode_with_offsets(input, &vocab, &AllowedSpecial::All).unwrap();
+        let chars = input.chars().collect::<Vec<_>>();
+        for span in &spans {
+            let token = decode(&[span.id], &vocab).unwrap();
+            assert_eq!(input[span.bytes.clone()], token);
+            assert_eq!(chars[span.chars.clone()].iter().collect::<String>(), token);
+        }
+        let special = spans
+            .iter()
+            .find(|span| Some(span.id) == vocab.special_token_id("<|sep|>"))
+            .unwrap();
+        assert_eq!(
+            (special.bytes.clone(), special.chars.clone()),
+            (5..12, 4..11)
+        );
+
+        let mut vocab = Vocabulary::byte_level(PreTokenizer::None);
+        vocab.learn("cafe", 0);
+        let spans = encode_with_offsets("é", &vocab, &AllowedSpecial::None).unwrap();
+        let offsets = spans
+            .into_iter()
+            .map(|span| (span.bytes, span.chars))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [(0..1, 0..1), (1..2, 0..1)]);
+    }
+}
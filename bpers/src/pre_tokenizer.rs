@@ -0,0 +1,195 @@
+use std::sync::LazyLock;
+
+use bincode::{
+    Decode, Encode,
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+};
+use fancy_regex::Regex;
+use thiserror::Error;
+
+/// The pattern GPT-2 uses to split text into words before applying merges.
+pub const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+static GPT2_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(GPT2_PATTERN).expect("GPT-2 pattern is valid"));
+
+#[derive(Error, Debug)]
+pub enum PreTokenizerError {
+    #[error("Invalid pre-tokenizer pattern: {0}")]
+    InvalidPattern(#[from] Box<fancy_regex::Error>),
+}
+
+/// Splits text into pieces before merges are applied.
+///
+/// Merges never span two pieces, both when learning and when encoding. Pieces always cover the
+/// whole input, so splitting is lossless.
+#[derive(Debug, Clone, Default)]
+pub enum PreTokenizer {
+    /// The whole text is a single piece.
+    #[default]
+    None,
+    /// Runs of whitespace and runs of non-whitespace characters are separate pieces.
+    Whitespace,
+    /// Pieces are matches of [`GPT2_PATTERN`].
+    Gpt2,
+    /// Pieces are matches of a user-supplied pattern. Text between matches forms pieces too.
+    Regex(Regex),
+}
+
+impl PreTokenizer {
+    /// Creates a `PreTokenizer` splitting on matches of `pattern`.
+    ///
+    /// # Errors
+    /// Returns an error if `pattern` is not a valid regular expression.
+    pub fn regex(pattern: &str) -> Result<Self, PreTokenizerError> {
+        let regex = Regex::new(pattern).map_err(Box::new)?;
+        Ok(Self::Regex(regex))
+    }
+
+    /// Splits `text` into non-empty pieces.
+    ///
+    /// Concatenating the returned pieces gives back `text`.
+    pub fn split<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        match self {
+            Self::None if text.is_empty() => Vec::new(),
+            Self::None => vec![text],
+            Self::Whitespace => split_whitespace_runs(text),
+            Self::Gpt2 => split_matches(&GPT2_REGEX, text),
+            Self::Regex(regex) => split_matches(regex, text),
+        }
+    }
+
+    /// Returns the pattern of the regex based pre-tokenizers.
+    pub fn pattern(&self) -> Option<&str> {
+        match self {
+            Self::None | Self::Whitespace => None,
+            Self::Gpt2 => Some(GPT2_PATTERN),
+            Self::Regex(regex) => Some(regex.as_str()),
+        }
+    }
+}
+
+impl PartialEq for PreTokenizer {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::None, Self::None)
+            | (Self::Whitespace, Self::Whitespace)
+            | (Self::Gpt2, Self::Gpt2) => true,
+            (Self::Regex(left), Self::Regex(right)) => left.as_str() == right.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for PreTokenizer {}
+
+impl Encode for PreTokenizer {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        match self {
+            Self::None => 0u8.encode(encoder),
+            Self::Whitespace => 1u8.encode(encoder),
+            Self::Gpt2 => 2u8.encode(encoder),
+            Self::Regex(regex) => {
+                3u8.encode(encoder)?;
+                regex.as_str().encode(encoder)
+            }
+        }
+    }
+}
+
+impl<Context> Decode<Context> for PreTokenizer {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        match u8::decode(decoder)? {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Whitespace),
+            2 => Ok(Self::Gpt2),
+            3 => {
+                let pattern = String::decode(decoder)?;
+                Self::regex(&pattern).map_err(|err| DecodeError::OtherString(err.to_string()))
+            }
+            found => Err(DecodeError::UnexpectedVariant {
+                type_name: "PreTokenizer",
+                allowed: &bincode::error::AllowedEnumVariants::Range { min: 0, max: 3 },
+                found: found as u32,
+            }),
+        }
+    }
+}
+
+bincode::impl_borrow_decode!(PreTokenizer);
+
+fn split_whitespace_runs(text: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut prev_is_whitespace = None;
+    for (i, char) in text.char_indices() {
+        let is_whitespace = char.is_whitespace();
+        if prev_is_whitespace.is_some_and(|prev| prev != is_whitespace) {
+            pieces.push(&text[start..i]);
+            start = i;
+        }
+        prev_is_whitespace = Some(is_whitespace);
+    }
+    if start < text.len() {
+        pieces.push(&text[start..]);
+    }
+    pieces
+}
+
+fn split_matches<'a>(regex: &Regex, text: &'a str) -> Vec<&'a str> {
+    let mut pieces = Vec::new();
+    let mut last = 0;
+    for found in regex.find_iter(text) {
+        // a match that fails (e.g. hits the backtrack limit) leaves the rest as one piece
+        let Ok(found) = found else {
+            break;
+        };
+        if found.start() == found.end() {
+            continue;
+        }
+        if found.start() > last {
+            pieces.push(&text[last..found.start()]);
+        }
+        pieces.push(found.as_str());
+        last = found.end();
+    }
+    if last < text.len() {
+        pieces.push(&text[last..]);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_are_lossless() {
+        let text = "Hello,  world! It's 2025...\n\tok";
+        let pre_tokenizers = [
+            PreTokenizer::None,
+            PreTokenizer::Whitespace,
+            PreTokenizer::Gpt2,
+            PreTokenizer::regex(r"\d+").unwrap(),
+        ];
+        for pre_tokenizer in pre_tokenizers {
+            assert_eq!(pre_tokenizer.split(text).concat(), text);
+        }
+
+        assert_eq!(
+            PreTokenizer::Whitespace.split("ab  cd\n"),
+            ["ab", "  ", "cd", "\n"]
+        );
+        assert_eq!(
+            PreTokenizer::Gpt2.split("It's  fine"),
+            ["It", "'s", " ", " fine"]
+        );
+        assert_eq!(
+            PreTokenizer::regex(r"\d+").unwrap().split("a12b3"),
+            ["a", "12", "b", "3"]
+        );
+    }
+}
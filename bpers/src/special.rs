@@ -0,0 +1,89 @@
+/// Which special tokens [`encode_with_special`](crate::encode_with_special) accepts in the input.
+///
+/// The literal text of a special token that is not allowed makes encoding fail, like tiktoken's
+/// `allowed_special` does.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AllowedSpecial {
+    /// No special token is allowed.
+    #[default]
+    None,
+    /// Every special token of the vocabulary is allowed.
+    All,
+    /// Only the special tokens with the listed texts are allowed.
+    Only(Vec<String>),
+}
+
+impl AllowedSpecial {
+    /// Returns `true` if the special token with the given `text` is allowed.
+    pub fn allows(&self, text: &str) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Only(texts) => texts.iter().any(|allowed| allowed == text),
+        }
+    }
+}
+
+/// A part of text split at the literal text of special tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Segment<'a> {
+    Text(&'a str),
+    Special(&'a str),
+}
+
+/// Splits `text` at every occurrence of the `specials`.
+///
+/// Occurrences are matched leftmost first, preferring the longest special token when several start
+/// at the same position. Text segments are never empty.
+pub(crate) fn split_specials<'a, 's>(
+    text: &'a str,
+    specials: impl IntoIterator<Item = &'s str>,
+) -> Vec<Segment<'a>> {
+    let mut found = specials
+        .into_iter()
+        .filter(|special| !special.is_empty())
+        .flat_map(|special| {
+            text.match_indices(special)
+                .map(|(start, special)| (start, special.len()))
+        })
+        .collect::<Vec<_>>();
+    found.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    let mut segments = Vec::new();
+    let mut last = 0;
+    for (start, len) in found {
+        if start < last {
+            continue;
+        }
+        if start > last {
+            segments.push(Segment::Text(&text[last..start]));
+        }
+        segments.push(Segment::Special(&text[start..start + len]));
+        last = start + len;
+    }
+    if last < text.len() {
+        segments.push(Segment::Text(&text[last..]));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_specials_prefers_leftmost_longest() {
+        let segments = split_specials("a<s><s>>b<s>", ["<s>", "<s>>"]);
+        assert_eq!(
+            segments,
+            [
+                Segment::Text("a"),
+                Segment::Special("<s>"),
+                Segment::Special("<s>>"),
+                Segment::Text("b"),
+                Segment::Special("<s>"),
+            ]
+        );
+        assert_eq!(split_specials("", ["<s>"]), []);
+    }
+}
@@ -0,0 +1,398 @@
+use std::io::{self, Read, Write};
+
+use foldhash::HashSet;
+use thiserror::Error;
+
+use crate::{
+    AllowedSpecial, Alphabet, Encoder, EncodingError, PreTokenizer, Token, Vocabulary,
+    special::Segment,
+};
+
+/// The number of bytes read from the input at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// The number of trailing pieces of a regex pre-tokenizer that may still change.
+///
+/// A match may depend on the text after it, e.g. `'` followed by `re` in the GPT-2 pattern.
+const HELD_BACK_PIECES: usize = 2;
+
+#[derive(Error, Debug)]
+pub enum StreamError {
+    #[error("Failed to access stream: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Encoding(#[from] EncodingError),
+}
+
+/// Encodes input read incrementally, writing token ids as soon as they are final.
+///
+/// Only the tail of the input after the last safe boundary is kept in memory. A boundary is safe
+/// when encoding the text before and after it separately gives the same ids as encoding it as a
+/// whole:
+/// * special tokens and invalid UTF-8 sequences of a byte-level vocabulary are always safe,
+/// * pieces of the pre-tokenizer are safe once the text after them cannot change them,
+/// * with [`PreTokenizer::None`] and [`PreTokenizer::Whitespace`], the boundary between two
+///   characters is safe if no token of the vocabulary spans it.
+///
+/// [`PreTokenizer::Gpt2`] only looks ahead as far as the next piece. A user-supplied
+/// [`PreTokenizer::Regex`] may look ahead arbitrarily far, so its pieces are only cut right after a
+/// newline, assuming the pattern does not look past the end of the next line. Text without any
+/// safe boundary, e.g. a single long line with a regex pre-tokenizer, is held back until the input
+/// ends.
+#[derive(Debug)]
+pub struct StreamEncoder<'a> {
+    encoder: Encoder<'a>,
+    allowed: AllowedSpecial,
+    joinable: HashSet<(u32, u32)>,
+    max_special_len: usize,
+}
+
+impl<'a> StreamEncoder<'a> {
+    /// Creates a `StreamEncoder` allowing no special tokens in the input.
+    pub fn new(vocab: &'a Vocabulary) -> Self {
+        Self::with_special(vocab, AllowedSpecial::None)
+    }
+
+    /// Creates a `StreamEncoder` turning the literal text of `allowed` special tokens into their
+    /// ids.
+    pub fn with_special(vocab: &'a Vocabulary, allowed: AllowedSpecial) -> Self {
+        let joinable = vocab
+            .id_to_token
+            .values()
+            .filter_map(|token| match token {
+                Token::Pair(pair) => Some((
+                    edge_symbol(vocab, pair.left, Edge::Last),
+                    edge_symbol(vocab, pair.right, Edge::First),
+                )),
+                _ => None,
+            })
+            .collect();
+        let max_special_len = vocab
+            .special_tokens()
+            .map(|(text, _)| text.len())
+            .max()
+            .unwrap_or(0);
+
+        Self {
+            encoder: Encoder::new(vocab),
+            allowed,
+            joinable,
+            max_special_len,
+        }
+    }
+
+    /// Returns the vocabulary the encoder encodes with.
+    pub fn vocabulary(&self) -> &'a Vocabulary {
+        self.encoder.vocabulary()
+    }
+
+    /// Encodes everything read from `reader`, passing the ids to `emit` as soon as they are final.
+    ///
+    /// # Returns
+    /// The number of emitted token ids.
+    ///
+    /// # Errors
+    /// Returns an error if reading fails, `emit` fails or the input cannot be encoded. The ids
+    /// emitted before the error stay emitted.
+    pub fn encode<R: Read>(
+        &self,
+        mut reader: R,
+        mut emit: impl FnMut(&[u32]) -> io::Result<()>,
+    ) -> Result<usize, StreamError> {
+        let mut pending = Vec::new();
+        let mut buffer = vec![0; READ_SIZE];
+        let mut n_tokens = 0;
+        loop {
+            let n_read = match reader.read(&mut buffer) {
+                Ok(n_read) => n_read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let is_end = n_read == 0;
+            pending.extend_from_slice(&buffer[..n_read]);
+
+            let boundary = if is_end {
+                pending.len()
+            } else {
+                self.safe_boundary(&pending)?
+            };
+            if boundary > 0 {
+                let encoded = self
+                    .encoder
+                    .encode_bytes_with_special(&pending[..boundary], &self.allowed)?;
+                emit(&encoded)?;
+                n_tokens += encoded.len();
+                pending.drain(..boundary);
+            }
+
+            if is_end {
+                return Ok(n_tokens);
+            }
+        }
+    }
+
+    /// Encodes everything read from `reader`, writing every id to `writer` as 4 little-endian
+    /// bytes.
+    ///
+    /// # Returns
+    /// The number of written token ids.
+    ///
+    /// # Errors
+    /// Returns an error if reading or writing fails or the input cannot be encoded.
+    pub fn encode_to<R: Read, W: Write>(
+        &self,
+        reader: R,
+        mut writer: W,
+    ) -> Result<usize, StreamError> {
+        let n_tokens = self.encode(reader, |ids| {
+            ids.iter()
+                .try_for_each(|id| writer.write_all(&id.to_le_bytes()))
+        })?;
+        writer.flush()?;
+        Ok(n_tokens)
+    }
+
+    /// Returns the last safe boundary of `pending` input, assuming more input follows.
+    ///
+    /// The bytes before the boundary may be encoded on their own, the rest has to wait for more
+    /// input. Returns `0` if there is no safe boundary yet.
+    ///
+    /// # Errors
+    /// Returns an error if a character based vocabulary gets input that is not valid UTF-8.
+    pub fn safe_boundary(&self, pending: &[u8]) -> Result<usize, EncodingError> {
+        // invalid sequences are encoded as pieces of their own, so only the text after the last
+        // one may change
+        let mut start = 0;
+        let text = loop {
+            match std::str::from_utf8(&pending[start..]) {
+                Ok(text) => break text,
+                Err(err) => {
+                    let valid = &pending[start..start + err.valid_up_to()];
+                    match err.error_len() {
+                        Some(len) if self.vocabulary().alphabet() == Alphabet::Bytes => {
+                            start += err.valid_up_to() + len;
+                        }
+                        Some(_) => return Err(EncodingError::InvalidUtf8),
+                        // an incomplete character at the end
+                        None => break std::str::from_utf8(valid).expect("prefix is valid"),
+                    }
+                }
+            }
+        };
+        Ok(start + self.text_boundary(text))
+    }
+
+    /// Returns the last safe boundary of valid UTF-8 `text`.
+    fn text_boundary(&self, text: &str) -> usize {
+        let vocab = self.vocabulary();
+        safe_text_boundary(
+            text,
+            vocab.split_specials(text),
+            self.max_special_len,
+            vocab.pre_tokenizer(),
+            |text, i| !self.is_spanned(text, i),
+        )
+    }
+
+    /// Returns `true` if a token may span the boundary at `i` of `text`.
+    fn is_spanned(&self, text: &str, i: usize) -> bool {
+        let vocab = self.vocabulary();
+        let (Some(before), Some(after)) = (text[..i].chars().next_back(), text[i..].chars().next())
+        else {
+            return false;
+        };
+        let before = vocab.symbols(before.encode_utf8(&mut [0; 4]));
+        let after = vocab.symbols(after.encode_utf8(&mut [0; 4]));
+        self.joinable
+            .contains(&(before[before.len() - 1], after[0]))
+    }
+}
+
+/// Returns the last boundary of `text` before which the special tokens and the pieces of the
+/// pre-tokenizer stay the same, whatever text follows.
+///
+/// `segments` are the segments of `text` split at special tokens at most `max_special_len` bytes
+/// long. The last piece of a pre-tokenizer that splits between any two characters of different
+/// kinds is also cut at the last `i` that `may_cut(text, i)` allows.
+pub(crate) fn safe_text_boundary(
+    text: &str,
+    segments: Vec<Segment<'_>>,
+    max_special_len: usize,
+    pre_tokenizer: &PreTokenizer,
+    may_cut: impl Fn(&str, usize) -> bool,
+) -> usize {
+    // a special token starting this close to the end may not be complete yet
+    let mut limit = text.len().saturating_sub(max_special_len.saturating_sub(1));
+    while !text.is_char_boundary(limit) {
+        limit -= 1;
+    }
+
+    let mut boundary = 0;
+    let mut offset = 0;
+    let mut segments = segments.into_iter().peekable();
+    while let Some(segment) = segments.next() {
+        if offset >= limit {
+            break;
+        }
+        match segment {
+            Segment::Special(special) => {
+                offset += special.len();
+                boundary = offset;
+            }
+            Segment::Text(part) => {
+                let end = offset + part.len();
+                if end < limit && matches!(segments.peek(), Some(Segment::Special(_))) {
+                    offset = end;
+                    boundary = offset;
+                } else {
+                    // a special token may cut the text anywhere after the limit
+                    let part = &part[..part.len().min(limit - offset)];
+                    boundary =
+                        boundary.max(offset + open_text_boundary(part, pre_tokenizer, may_cut));
+                    break;
+                }
+            }
+        }
+    }
+    boundary
+}
+
+/// Returns the last safe boundary of `text` that more text may follow.
+fn open_text_boundary(
+    text: &str,
+    pre_tokenizer: &PreTokenizer,
+    may_cut: impl Fn(&str, usize) -> bool,
+) -> usize {
+    let pieces = pre_tokenizer.split(text);
+    let held_back = match pre_tokenizer {
+        PreTokenizer::None | PreTokenizer::Whitespace => 1,
+        PreTokenizer::Gpt2 | PreTokenizer::Regex(_) => HELD_BACK_PIECES,
+    };
+    let n_final = pieces.len().saturating_sub(held_back);
+    let mut cut = pieces[..n_final]
+        .iter()
+        .map(|piece| piece.len())
+        .sum::<usize>();
+
+    if held_back == 1 {
+        // these split between any two characters of different kinds, so the last piece can be
+        // cut wherever no merge spans
+        return text
+            .char_indices()
+            .rev()
+            .take_while(|&(i, _)| i > cut)
+            .find(|&(i, _)| may_cut(text, i))
+            .map_or(cut, |(i, _)| i);
+    }
+
+    // the end of the text may change how the pieces before it are split, e.g. trailing
+    // whitespace, so a boundary is only safe if the text before it splits the same on its own.
+    // A custom pattern may look ahead any distance, so it is only cut at the end of a line
+    let is_regex = matches!(pre_tokenizer, PreTokenizer::Regex(_));
+    for n_pieces in (1..=n_final).rev() {
+        if (!is_regex || text[..cut].ends_with('\n'))
+            && pre_tokenizer.split(&text[..cut]) == pieces[..n_pieces]
+        {
+            return cut;
+        }
+        cut -= pieces[n_pieces - 1].len();
+    }
+    0
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Edge {
+    First,
+    Last,
+}
+
+/// Returns the first or last base symbol the token with the given `id` expands to.
+fn edge_symbol(vocab: &Vocabulary, mut id: u32, edge: Edge) -> u32 {
+    while let Some(Token::Pair(pair)) = vocab.id_to_token.get(&id) {
+        id = match edge {
+            Edge::First => pair.left,
+            Edge::Last => pair.right,
+        };
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_bytes_with_special;
+
+    /// Reads a few bytes at a time.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streaming_matches_encoding_at_once() {
+        let corpus = "the cat sat on the mat, it's the cat's mat.\nthe rat ate the cat's hat! ";
+        let input = [
+            corpus.as_bytes(),
+            b"<|end|>the bat\xff<|end|",
+            "sat 🐈".as_bytes(),
+        ]
+        .concat();
+        let allowed = AllowedSpecial::All;
+
+        let pre_tokenizers = [
+            PreTokenizer::None,
+            PreTokenizer::Whitespace,
+            PreTokenizer::Gpt2,
+        ];
+        for pre_tokenizer in pre_tokenizers {
+            let mut vocab = Vocabulary::byte_level(pre_tokenizer);
+            vocab.learn(&corpus.repeat(3), 60);
+            vocab.add_special_token("<|end|>");
+            let expected = encode_bytes_with_special(&input, &vocab, &allowed).unwrap();
+
+            let stream = StreamEncoder::with_special(&vocab, allowed.clone());
+            let mut encoded = Vec::new();
+            let n_tokens = stream
+                .encode(Trickle(&input), |ids| {
+                    encoded.extend_from_slice(ids);
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(encoded, expected);
+            assert_eq!(n_tokens, expected.len());
+
+            let mut written = Vec::new();
+            stream.encode_to(Trickle(&input), &mut written).unwrap();
+            assert_eq!(written.len(), expected.len() * 4);
+        }
+
+        // words are pieces of their own only if a `!` follows on the same line
+        let pre_tokenizer = PreTokenizer::regex(r"\w+(?=[^\n]*!)|\w|\W").unwrap();
+        let mut vocab = Vocabulary::with_pre_tokenizer(pre_tokenizer);
+        vocab.learn(&"the cat sat on the mat!\n".repeat(3), 20);
+        let input = "the cat sat on the mat!\nthe mat sat on the cat\nthe cat sat!\n";
+        let expected = crate::encode(input, &vocab).unwrap();
+        let mut encoded = Vec::new();
+        StreamEncoder::new(&vocab)
+            .encode(Trickle(input.as_bytes()), |ids| {
+                encoded.extend_from_slice(ids);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(encoded, expected);
+        let stream = StreamEncoder::new(&vocab);
+        assert_eq!(stream.safe_boundary(b"the cat sat").unwrap(), 0);
+
+        let mut vocab = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        vocab.learn(corpus, 20);
+        let stream = StreamEncoder::new(&vocab);
+        assert_eq!(stream.safe_boundary(b"the th").unwrap(), "the ".len());
+        assert!(stream.safe_boundary(b"the \xff").is_err());
+    }
+}
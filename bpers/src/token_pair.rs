@@ -4,6 +4,7 @@ use bincode::{Decode, Encode};
 pub enum Token {
     Lonely(Lonely),
     Pair(Pair),
+    Special(Special),
 }
 
 impl Token {
@@ -28,6 +29,20 @@ impl Lonely {
     }
 }
 
+/// A reserved token that is never merged. Holds its own id; the text is kept by the vocabulary.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
+pub struct Special(pub u32);
+
+impl Special {
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    pub fn as_token(&self) -> Token {
+        Token::Special(*self)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Encode, Decode)]
 pub struct Pair {
     pub left: u32,
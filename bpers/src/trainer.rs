@@ -0,0 +1,677 @@
+use std::{cmp::Reverse, collections::BinaryHeap, thread};
+
+use foldhash::{HashMap, HashMapExt};
+
+use crate::{Pair, PairCounting};
+
+/// Marks a missing neighbour in the linked token list.
+const NONE: u32 = u32::MAX;
+
+/// A single symbol of the corpus, linked to its live neighbours within the same segment.
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    token: u32,
+    prev: u32,
+    next: u32,
+    /// How many times the segment of this node occurs in the corpus.
+    weight: u64,
+    starts_segment: bool,
+    removed: bool,
+}
+
+/// An entry of the pair priority queue.
+///
+/// Entries are ordered by frequency and then by the position of the first occurrence of the pair.
+/// A later first occurrence wins a tie, which mirrors `Iterator::max_by_key` over an `IndexMap`
+/// filled in sequence order (it returns the last maximum).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Candidate {
+    freq: u64,
+    first: u32,
+    pair: Pair,
+    /// The overlapping frequency of the pair when the entry was pushed.
+    bound: u64,
+    /// The number of merges performed when `freq` was lowered to the non-overlapping frequency.
+    refined_at: Option<usize>,
+}
+
+/// A pair formed by the last live node of one shard and the first live node of the next one.
+#[derive(Debug, Clone, Copy)]
+struct Boundary {
+    left_shard: usize,
+    right_shard: usize,
+    pos: u32,
+    weight: u64,
+    pair: Pair,
+}
+
+/// Where a pair occurs inside a shard.
+///
+/// Positions are dropped lazily: a position the pair no longer occurs at stays in the heap until
+/// it is read or outnumbers the others. The pair never occurs at such a position again, as the
+/// tokens around it only change to newly merged ones.
+#[derive(Debug, Default)]
+struct Occurrences {
+    /// Positions (index of the left node) of the pair, the first one on top. Some may be stale.
+    positions: BinaryHeap<Reverse<u32>>,
+    /// The number of positions the pair still occurs at.
+    len: usize,
+    /// Sum of the weights of the positions the pair still occurs at.
+    freq: u64,
+}
+
+/// Tells whether `pair` occurs at `pos`, the index of its left node in a shard starting at
+/// `offset`.
+fn occurs_at(nodes: &[Node], offset: u32, pos: u32, pair: Pair) -> bool {
+    let left = &nodes[(pos - offset) as usize];
+    !left.removed
+        && left.next != NONE
+        && left.token == pair.left
+        && nodes[(left.next - offset) as usize].token == pair.right
+}
+
+/// A contiguous part of the corpus.
+///
+/// Node indices are global, so positions from different shards can be compared directly. The
+/// links of the first and the last node of a shard are cut; pairs spanning two shards are tracked
+/// by the [`Trainer`] as boundaries.
+struct Shard {
+    offset: u32,
+    nodes: Vec<Node>,
+    first: u32,
+    last: u32,
+    /// Every adjacent pair inside the shard.
+    occurrences: HashMap<Pair, Occurrences>,
+}
+
+impl Shard {
+    fn new(offset: u32, mut nodes: Vec<Node>) -> Self {
+        let (first, last) = match nodes.len() {
+            0 => (NONE, NONE),
+            len => (offset, offset + len as u32 - 1),
+        };
+        // cut the links leaving the shard
+        if let Some(node) = nodes.first_mut() {
+            node.prev = NONE;
+        }
+        if let Some(node) = nodes.last_mut() {
+            node.next = NONE;
+        }
+
+        let mut shard = Self {
+            offset,
+            nodes,
+            first,
+            last,
+            occurrences: HashMap::new(),
+        };
+        for i in 0..shard.nodes.len() {
+            let node = shard.nodes[i];
+            if node.next != NONE {
+                let pair = Pair::new(node.token, shard.node(node.next).token);
+                let occurrences = shard.occurrences.entry(pair).or_default();
+                occurrences.positions.push(Reverse(offset + i as u32));
+                occurrences.len += 1;
+                occurrences.freq += node.weight;
+            }
+        }
+        shard
+    }
+
+    fn node(&self, idx: u32) -> &Node {
+        &self.nodes[(idx - self.offset) as usize]
+    }
+
+    fn node_mut(&mut self, idx: u32) -> &mut Node {
+        &mut self.nodes[(idx - self.offset) as usize]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.first == NONE
+    }
+
+    fn freq(&self, pair: &Pair) -> u64 {
+        self.occurrences
+            .get(pair)
+            .map_or(0, |occurrences| occurrences.freq)
+    }
+
+    /// Returns the first position of `pair`, dropping the stale positions before it.
+    fn first_occurrence(&mut self, pair: &Pair) -> Option<u32> {
+        let positions = &mut self.occurrences.get_mut(pair)?.positions;
+        while let Some(&Reverse(pos)) = positions.peek() {
+            if occurs_at(&self.nodes, self.offset, pos, *pair) {
+                return Some(pos);
+            }
+            positions.pop();
+        }
+        None
+    }
+
+    /// Returns the positions of `pair` in order.
+    fn positions(&self, pair: &Pair) -> Vec<u32> {
+        let Some(occurrences) = self.occurrences.get(pair) else {
+            return Vec::new();
+        };
+        let mut positions = occurrences
+            .positions
+            .iter()
+            .map(|&Reverse(pos)| pos)
+            .filter(|&pos| occurs_at(&self.nodes, self.offset, pos, *pair))
+            .collect::<Vec<_>>();
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+
+    /// Tells whether the last node is still free after merging `(token, token)` inside the shard,
+    /// so it can be merged with the first node of the next shard.
+    fn ends_free(&self, token: u32, skip_first: bool) -> bool {
+        // greedy left to right merging pairs up a run of equal tokens from its start
+        let mut run = 0;
+        let mut idx = self.last;
+        while idx != NONE && self.node(idx).token == token {
+            run += 1;
+            if idx == self.first && skip_first {
+                run -= 1;
+            }
+            idx = self.node(idx).prev;
+        }
+        run % 2 == 1
+    }
+
+    /// Replaces every occurrence of `pair` inside the shard with `merged_id`, left to right.
+    ///
+    /// When `skip_first` is set the first node is already taken by a merge with the previous shard.
+    ///
+    /// Returns the pairs whose occurrences changed and the total weight of the replacements.
+    fn merge(&mut self, pair: Pair, merged_id: u32, skip_first: bool) -> (Vec<Pair>, u64) {
+        let mut touched: Vec<Pair> = Vec::new();
+        let mut merged = 0;
+        let positions = self.positions(&pair);
+        if self.occurrences.remove(&pair).is_none() {
+            return (touched, merged);
+        }
+
+        for pos in positions {
+            let left = *self.node(pos);
+            // the position might have been consumed by the previous merge (e.g. `aaa`)
+            if left.removed
+                || left.token != pair.left
+                || left.next == NONE
+                || (skip_first && pos == self.first)
+            {
+                continue;
+            }
+            let right = *self.node(left.next);
+            if right.token != pair.right {
+                continue;
+            }
+
+            if left.prev != NONE {
+                let prev_pair = Pair::new(self.node(left.prev).token, pair.left);
+                self.remove_occurrence(prev_pair, left.prev, &mut touched);
+            }
+            if right.next != NONE {
+                let next_pair = Pair::new(pair.right, self.node(right.next).token);
+                self.remove_occurrence(next_pair, left.next, &mut touched);
+            }
+
+            merged += left.weight;
+            self.node_mut(left.next).removed = true;
+            let node = self.node_mut(pos);
+            node.token = merged_id;
+            node.next = right.next;
+            if right.next != NONE {
+                self.node_mut(right.next).prev = pos;
+            }
+            if left.next == self.last {
+                self.last = pos;
+            }
+
+            if left.prev != NONE {
+                let prev_pair = Pair::new(self.node(left.prev).token, merged_id);
+                self.add_occurrence(prev_pair, left.prev, &mut touched);
+            }
+            if right.next != NONE {
+                let next_pair = Pair::new(merged_id, self.node(right.next).token);
+                self.add_occurrence(next_pair, pos, &mut touched);
+            }
+        }
+
+        (touched, merged)
+    }
+
+    /// Replaces the token of the last node, which got merged with the next shard.
+    fn replace_last(&mut self, merged_id: u32, touched: &mut Vec<Pair>) {
+        let last = *self.node(self.last);
+        if last.prev != NONE {
+            let prev_token = self.node(last.prev).token;
+            self.remove_occurrence(Pair::new(prev_token, last.token), last.prev, touched);
+        }
+        self.node_mut(self.last).token = merged_id;
+        if last.prev != NONE {
+            let prev_token = self.node(last.prev).token;
+            self.add_occurrence(Pair::new(prev_token, merged_id), last.prev, touched);
+        }
+    }
+
+    /// Removes the first node, which got merged into the previous shard.
+    fn remove_first(&mut self, touched: &mut Vec<Pair>) {
+        let first = *self.node(self.first);
+        self.node_mut(self.first).removed = true;
+        if first.next != NONE {
+            let next_token = self.node(first.next).token;
+            self.remove_occurrence(Pair::new(first.token, next_token), self.first, touched);
+            self.node_mut(first.next).prev = NONE;
+        }
+
+        // the segment may end right here, so look for the next live node in the shard
+        if self.first == self.last {
+            self.first = NONE;
+            self.last = NONE;
+        } else {
+            self.first = (self.first + 1..=self.last)
+                .find(|&idx| !self.node(idx).removed)
+                .expect("the last node is live");
+        }
+    }
+
+    /// Records that `pair` now occurs at `pos`. The nodes must already hold the pair.
+    fn add_occurrence(&mut self, pair: Pair, pos: u32, touched: &mut Vec<Pair>) {
+        let weight = self.node(pos).weight;
+        let occurrences = self.occurrences.entry(pair).or_default();
+        occurrences.positions.push(Reverse(pos));
+        occurrences.len += 1;
+        occurrences.freq += weight;
+        if occurrences.positions.len() > 2 * occurrences.len + 16 {
+            let (nodes, offset) = (&self.nodes, self.offset);
+            occurrences
+                .positions
+                .retain(|&Reverse(pos)| occurs_at(nodes, offset, pos, pair));
+        }
+        touched.push(pair);
+    }
+
+    /// Records that `pair` no longer occurs at `pos`, which is about to change.
+    fn remove_occurrence(&mut self, pair: Pair, pos: u32, touched: &mut Vec<Pair>) {
+        let weight = self.node(pos).weight;
+        if let Some(occurrences) = self.occurrences.get_mut(&pair) {
+            occurrences.len -= 1;
+            occurrences.freq -= weight;
+            if occurrences.len == 0 {
+                self.occurrences.remove(&pair);
+            }
+            touched.push(pair);
+        }
+    }
+}
+
+/// Incremental state of a BPE training run.
+///
+/// The corpus is kept as a linked list of symbols and every adjacent pair knows the positions
+/// (index of its left node) where it occurs. Merging a pair only touches those positions and their
+/// direct neighbours, instead of recounting and rewriting the whole corpus.
+///
+/// The corpus is made of segments, each with its own weight. Pairs never span two segments.
+///
+/// The corpus can be split into several shards which are built and merged on separate threads.
+/// Frequencies and first occurrences are reduced over the shards in order, so the result does not
+/// depend on the number of shards.
+///
+/// Every symbol of the corpus takes about 30 bytes: its node and its position in the occurrences
+/// of its pair. The pairs created by merges add to that. Aggregating repeated segments, e.g. with
+/// a pre-tokenizer, keeps a large corpus small.
+pub(crate) struct Trainer {
+    shards: Vec<Shard>,
+    boundaries: Vec<Boundary>,
+    /// Max-heap of pair candidates. Entries are lazily invalidated: an entry is only trusted if it
+    /// still matches the current frequency and first occurrence of its pair.
+    queue: BinaryHeap<Candidate>,
+    /// The number of tokens in the corpus, counting every segment as many times as it occurs.
+    sequence_len: u64,
+    counting: PairCounting,
+    n_merges: usize,
+}
+
+impl Trainer {
+    /// Creates a trainer over a tokenized corpus, split into `n_shards` shards.
+    ///
+    /// # Arguments
+    /// * `segments` - Tokenized segments of the corpus along with their weights.
+    /// * `n_shards` - The number of shards to build and merge in parallel.
+    /// * `counting` - How occurrences of pairs of equal tokens are counted.
+    ///
+    /// # Panics
+    /// Panics if the segments hold `u32::MAX` symbols or more.
+    pub(crate) fn new<S, T>(segments: S, n_shards: usize, counting: PairCounting) -> Self
+    where
+        S: IntoIterator<Item = (T, u64)>,
+        T: IntoIterator<Item = u32>,
+    {
+        let mut nodes: Vec<Node> = Vec::new();
+        for (tokens, weight) in segments {
+            let start = nodes.len();
+            nodes.extend(tokens.into_iter().map(|token| Node {
+                token,
+                prev: NONE,
+                next: NONE,
+                weight,
+                starts_segment: false,
+                removed: false,
+            }));
+            let end = nodes.len();
+            assert!(end < NONE as usize, "too many symbols to train on");
+            for (idx, node) in nodes.iter_mut().enumerate().skip(start) {
+                node.prev = if idx == start { NONE } else { idx as u32 - 1 };
+                node.next = if idx + 1 == end { NONE } else { idx as u32 + 1 };
+                node.starts_segment = idx == start;
+            }
+        }
+
+        let n_shards = n_shards.clamp(1, nodes.len().max(1));
+        let shard_len = nodes.len().div_ceil(n_shards).max(1);
+
+        let shards: Vec<Shard> = if n_shards == 1 {
+            vec![Shard::new(0, nodes)]
+        } else {
+            // split off the last shard first, so every node is copied at most once
+            let mut chunks = Vec::with_capacity(n_shards);
+            while nodes.len() > shard_len {
+                let start = (nodes.len() - 1) / shard_len * shard_len;
+                chunks.push(nodes.split_off(start));
+                nodes.shrink_to_fit();
+            }
+            chunks.push(nodes);
+            chunks.reverse();
+
+            thread::scope(|scope| {
+                let handles = chunks
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, chunk)| {
+                        scope.spawn(move || Shard::new((i * shard_len) as u32, chunk))
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("shard construction panicked"))
+                    .collect()
+            })
+        };
+
+        let sequence_len = shards
+            .iter()
+            .flat_map(|shard| &shard.nodes)
+            .map(|node| node.weight)
+            .sum();
+        let mut trainer = Self {
+            shards,
+            boundaries: Vec::new(),
+            queue: BinaryHeap::new(),
+            sequence_len,
+            counting,
+            n_merges: 0,
+        };
+        trainer.boundaries = trainer.find_boundaries();
+        trainer.push_candidates(trainer.pairs());
+
+        trainer
+    }
+
+    /// Pops the most frequent pair along with its frequency.
+    ///
+    /// When counting non-overlapping occurrences, the queue holds the overlapping frequency of
+    /// pairs of equal tokens, which is an upper bound. Such a pair is only returned once its exact
+    /// frequency has been computed and it still comes out on top.
+    ///
+    /// Returns `None` when no adjacent pairs are left.
+    pub(crate) fn pop_best(&mut self) -> Option<(Pair, u64)> {
+        while let Some(candidate) = self.queue.pop() {
+            let pair = candidate.pair;
+            if self.freq(&pair) != candidate.bound
+                || self.first_occurrence(&pair) != Some(candidate.first)
+            {
+                continue;
+            }
+            if self.counting == PairCounting::Overlapping || pair.left != pair.right {
+                return Some((pair, candidate.freq));
+            }
+            match candidate.refined_at {
+                Some(n_merges) if n_merges == self.n_merges => {
+                    return Some((pair, candidate.freq));
+                }
+                // other occurrences may have changed since, so refine again
+                Some(_) => self.queue.push(Candidate {
+                    freq: candidate.bound,
+                    refined_at: None,
+                    ..candidate
+                }),
+                None => self.queue.push(Candidate {
+                    freq: self.non_overlapping_freq(&pair),
+                    refined_at: Some(self.n_merges),
+                    ..candidate
+                }),
+            }
+        }
+        None
+    }
+
+    /// Returns the current number of tokens in the corpus.
+    pub(crate) fn sequence_len(&self) -> u64 {
+        self.sequence_len
+    }
+
+    /// Replaces every occurrence of `pair` with `merged_id`, left to right.
+    ///
+    /// Returns the number of replacements, weighted by segment counts.
+    pub(crate) fn merge(&mut self, pair: Pair, merged_id: u32) -> u64 {
+        // decide which boundary pairs get merged; only runs of equal tokens need care, as the
+        // first node of a shard may then be taken by the previous shard
+        let mut skip_first = vec![false; self.shards.len()];
+        let mut merged_boundaries = Vec::new();
+        for boundary in self.boundaries.iter().filter(|b| b.pair == pair) {
+            if pair.left != pair.right
+                || self.shards[boundary.left_shard]
+                    .ends_free(pair.left, skip_first[boundary.left_shard])
+            {
+                skip_first[boundary.right_shard] = true;
+                merged_boundaries.push(*boundary);
+            }
+        }
+
+        let mut jobs = self
+            .shards
+            .iter_mut()
+            .zip(skip_first)
+            .filter(|(shard, _)| shard.occurrences.contains_key(&pair))
+            .collect::<Vec<_>>();
+        let (mut touched, mut merged): (Vec<Pair>, u64) = if jobs.len() == 1 {
+            let (shard, skip_first) = jobs.pop().expect("exactly one job");
+            shard.merge(pair, merged_id, skip_first)
+        } else {
+            thread::scope(|scope| {
+                let handles = jobs
+                    .into_iter()
+                    .map(|(shard, skip_first)| {
+                        scope.spawn(move || shard.merge(pair, merged_id, skip_first))
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("shard merge panicked"))
+                    .fold((Vec::new(), 0), |(mut touched, merged), (pairs, n)| {
+                        touched.extend(pairs);
+                        (touched, merged + n)
+                    })
+            })
+        };
+
+        for boundary in merged_boundaries {
+            self.shards[boundary.left_shard].replace_last(merged_id, &mut touched);
+            self.shards[boundary.right_shard].remove_first(&mut touched);
+            merged += boundary.weight;
+        }
+        self.sequence_len -= merged;
+        self.n_merges += 1;
+
+        let boundaries = self.find_boundaries();
+        touched.extend(self.boundaries.iter().map(|boundary| boundary.pair));
+        touched.extend(boundaries.iter().map(|boundary| boundary.pair));
+        self.boundaries = boundaries;
+
+        touched.sort_unstable();
+        touched.dedup();
+        self.push_candidates(touched);
+
+        // start over once stale entries outnumber the pairs
+        let n_pairs = self
+            .shards
+            .iter()
+            .map(|shard| shard.occurrences.len())
+            .sum::<usize>()
+            + self.boundaries.len();
+        if self.queue.len() > 2 * n_pairs + 1024 {
+            self.queue.clear();
+            self.push_candidates(self.pairs());
+        }
+
+        merged
+    }
+
+    /// Consumes the trainer and returns the current tokens of every non-empty segment.
+    pub(crate) fn into_segments(self) -> Vec<Vec<u32>> {
+        self.segments()
+            .into_iter()
+            .map(|(tokens, _)| tokens)
+            .collect()
+    }
+
+    /// Returns the current tokens of every non-empty segment along with its weight.
+    ///
+    /// A trainer created from the returned segments continues exactly like this one.
+    pub(crate) fn segments(&self) -> Vec<(Vec<u32>, u64)> {
+        let mut segments: Vec<(Vec<u32>, u64)> = Vec::new();
+        for node in self.shards.iter().flat_map(|shard| &shard.nodes) {
+            if node.starts_segment {
+                segments.push((Vec::new(), node.weight));
+            }
+            if !node.removed {
+                segments
+                    .last_mut()
+                    .expect("the first node starts a segment")
+                    .0
+                    .push(node.token);
+            }
+        }
+        segments
+    }
+
+    /// Returns every adjacent pair of the corpus, in order.
+    fn pairs(&self) -> Vec<Pair> {
+        let mut pairs = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.occurrences.keys().copied())
+            .chain(self.boundaries.iter().map(|boundary| boundary.pair))
+            .collect::<Vec<_>>();
+        pairs.sort_unstable();
+        pairs.dedup();
+        pairs
+    }
+
+    fn find_boundaries(&self) -> Vec<Boundary> {
+        let mut boundaries = Vec::new();
+        let mut shards = self
+            .shards
+            .iter()
+            .enumerate()
+            .filter(|(_, shard)| !shard.is_empty());
+        let Some(mut left) = shards.next() else {
+            return boundaries;
+        };
+        for right in shards {
+            let last = left.1.node(left.1.last);
+            let first = right.1.node(right.1.first);
+            // removed nodes never start a segment, so both nodes belong to the same segment
+            if !first.starts_segment {
+                boundaries.push(Boundary {
+                    left_shard: left.0,
+                    right_shard: right.0,
+                    pos: left.1.last,
+                    weight: last.weight,
+                    pair: Pair::new(last.token, first.token),
+                });
+            }
+            left = right;
+        }
+        boundaries
+    }
+
+    fn freq(&self, pair: &Pair) -> u64 {
+        let inner: u64 = self.shards.iter().map(|shard| shard.freq(pair)).sum();
+        let boundaries: u64 = self
+            .boundaries
+            .iter()
+            .filter(|boundary| boundary.pair == *pair)
+            .map(|boundary| boundary.weight)
+            .sum();
+        inner + boundaries
+    }
+
+    fn first_occurrence(&mut self, pair: &Pair) -> Option<u32> {
+        // a boundary position is the last node of its left shard
+        let mut boundaries = self.boundaries.iter().peekable();
+        for (i, shard) in self.shards.iter_mut().enumerate() {
+            if let Some(pos) = shard.first_occurrence(pair) {
+                return Some(pos);
+            }
+            while let Some(boundary) = boundaries.next_if(|b| b.left_shard == i) {
+                if boundary.pair == *pair {
+                    return Some(boundary.pos);
+                }
+            }
+        }
+        None
+    }
+
+    /// Counts the occurrences of `pair` that merging it replaces, i.e. without overlaps.
+    fn non_overlapping_freq(&self, pair: &Pair) -> u64 {
+        // merging goes left to right, so an occurrence is replaced unless its left node is the
+        // right node of the previous replaced occurrence
+        let mut freq = 0;
+        let mut last_right = NONE;
+        let mut boundaries = self.boundaries.iter().peekable();
+        for (i, shard) in self.shards.iter().enumerate() {
+            for pos in shard.positions(pair) {
+                if pos != last_right {
+                    let node = shard.node(pos);
+                    freq += node.weight;
+                    last_right = node.next;
+                }
+            }
+            while let Some(boundary) = boundaries.next_if(|b| b.left_shard == i) {
+                if boundary.pair == *pair && boundary.pos != last_right {
+                    freq += boundary.weight;
+                    last_right = self.shards[boundary.right_shard].first;
+                }
+            }
+        }
+        freq
+    }
+
+    fn push_candidates(&mut self, pairs: Vec<Pair>) {
+        for pair in pairs {
+            if let Some(first) = self.first_occurrence(&pair) {
+                let freq = self.freq(&pair);
+                self.queue.push(Candidate {
+                    freq,
+                    first,
+                    pair,
+                    bound: freq,
+                    refined_at: None,
+                });
+            }
+        }
+    }
+}
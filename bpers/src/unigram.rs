@@ -0,0 +1,628 @@
+use bincode::{Decode, Encode};
+use foldhash::HashMap;
+
+use crate::{
+    AllowedSpecial, Corpus, EncodingError, PreTokenizer,
+    corpus::FoldIndexMap,
+    special::{Segment, split_specials},
+};
+
+/// Parameters of a Unigram learning run.
+#[derive(Debug, Clone)]
+pub struct UnigramConfig {
+    /// The vocabulary size to prune down to, counting special tokens.
+    ///
+    /// Single characters are never pruned, so the vocabulary ends up larger if the corpus has
+    /// more distinct characters than that.
+    pub vocab_size: usize,
+    /// The max number of characters of a piece.
+    pub max_piece_len: usize,
+    /// The number of pieces to start pruning from.
+    ///
+    /// While seeding, the counts of rare substrings are dropped whenever more than twice this many
+    /// are counted, so substrings of a large corpus that only become frequent late may be
+    /// undercounted.
+    pub seed_size: usize,
+    /// The fraction of pieces kept by every pruning round.
+    pub shrink_factor: f64,
+    /// The number of EM iterations before every pruning round.
+    pub n_em_iterations: u32,
+}
+
+impl UnigramConfig {
+    /// Creates a `UnigramConfig` pruning the vocabulary down to `vocab_size` tokens.
+    pub fn new(vocab_size: usize) -> Self {
+        Self {
+            vocab_size,
+            max_piece_len: 16,
+            seed_size: 1_000_000,
+            shrink_factor: 0.75,
+            n_em_iterations: 2,
+        }
+    }
+}
+
+/// Summary of a Unigram learning run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnigramReport {
+    /// The size of the vocabulary after the run.
+    pub vocab_size: usize,
+    /// The number of pruning rounds performed.
+    pub n_rounds: u32,
+    /// The log-likelihood of the corpus under the final model.
+    pub log_likelihood: f64,
+}
+
+/// A token of a [`Unigram`] model.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct Piece {
+    pub text: String,
+    /// The log probability of the piece. Always 0 for special tokens.
+    pub score: f64,
+    pub special: bool,
+}
+
+/// A Unigram language model tokenizer, as used by SentencePiece.
+///
+/// Every piece has a probability and text is split into the most probable sequence of pieces.
+/// Pieces never span two pieces of the pre-tokenizer, just like merges of a [`Vocabulary`]
+/// never do.
+///
+/// [`Vocabulary`]: crate::Vocabulary
+#[derive(Debug, Default, Encode, Decode)]
+pub struct Unigram {
+    /// Pieces by their ids.
+    pieces: Vec<Piece>,
+    /// Ids of the pieces that are not special tokens.
+    piece_to_id: HashMap<String, u32>,
+    /// The max number of characters of a piece.
+    max_piece_len: usize,
+    pre_tokenizer: PreTokenizer,
+}
+
+impl Unigram {
+    /// Creates a new empty `Unigram` model.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new empty `Unigram` model splitting text with `pre_tokenizer`.
+    pub fn with_pre_tokenizer(pre_tokenizer: PreTokenizer) -> Self {
+        Self {
+            pre_tokenizer,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the pre-tokenizer applied to text when learning and encoding.
+    pub fn pre_tokenizer(&self) -> &PreTokenizer {
+        &self.pre_tokenizer
+    }
+
+    /// Returns the pieces of the model, indexed by id.
+    pub fn pieces(&self) -> &[Piece] {
+        &self.pieces
+    }
+
+    /// Returns the number of tokens, counting special tokens.
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Returns `true` if the model has no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    /// Reserves a special token for `text` and returns its id.
+    ///
+    /// Registering the same text again returns the existing id.
+    ///
+    /// # Panics
+    /// Panics if `text` is empty.
+    pub fn add_special_token(&mut self, text: &str) -> u32 {
+        assert!(!text.is_empty(), "special token text must not be empty");
+        if let Some(id) = self.special_token_id(text) {
+            return id;
+        }
+        self.pieces.push(Piece {
+            text: text.to_string(),
+            score: 0.0,
+            special: true,
+        });
+        self.pieces.len() as u32 - 1
+    }
+
+    /// Returns the id of the special token with the given `text`.
+    pub fn special_token_id(&self, text: &str) -> Option<u32> {
+        self.pieces
+            .iter()
+            .position(|piece| piece.special && piece.text == text)
+            .map(|id| id as u32)
+    }
+
+    /// Iterates over the texts and ids of the special tokens.
+    pub fn special_tokens(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.special)
+            .map(|(id, piece)| (piece.text.as_str(), id as u32))
+    }
+
+    /// Learns the pieces of the model from an aggregated corpus.
+    ///
+    /// Starts from the most frequent substrings of the corpus, then alternates EM iterations with
+    /// pruning the pieces whose removal hurts the likelihood of the corpus the least, until the
+    /// vocabulary is small enough. Previously learned pieces are replaced; special tokens are
+    /// kept and take the first ids, followed by the pieces from the most to the least probable.
+    ///
+    /// The pre-tokenizer of the `corpus` becomes the pre-tokenizer of the model, and its special
+    /// tokens are added to the model.
+    ///
+    /// # Arguments
+    /// * `corpus` - The aggregated text corpus.
+    /// * `config` - Parameters of the learning run.
+    ///
+    /// # Returns
+    /// A summary of the run.
+    pub fn learn_corpus(&mut self, corpus: &Corpus, config: &UnigramConfig) -> UnigramReport {
+        self.pre_tokenizer = corpus.pre_tokenizer().clone();
+        for text in corpus.special_tokens() {
+            self.add_special_token(text);
+        }
+        let specials = self
+            .pieces
+            .drain(..)
+            .filter(|piece| piece.special)
+            .collect::<Vec<_>>();
+
+        let chunks = corpus.iter().collect::<Vec<_>>();
+        let max_piece_len = config.max_piece_len.max(1);
+        let target = config.vocab_size.saturating_sub(specials.len());
+        let mut pieces = seed_pieces(&chunks, max_piece_len, config.seed_size);
+
+        let mut n_rounds = 0;
+        let log_likelihood = loop {
+            for _ in 0..config.n_em_iterations.max(1) {
+                let (counts, _) = expectation(&chunks, &pieces, max_piece_len);
+                pieces = maximization(pieces, &counts);
+            }
+            // expected counts of the pieces kept by the last M step
+            let (counts, log_likelihood) = expectation(&chunks, &pieces, max_piece_len);
+            if pieces.len() <= target {
+                break log_likelihood;
+            }
+            let size = (pieces.len() as f64 * config.shrink_factor) as usize;
+            let pruned = prune(pieces.clone(), &counts, size.max(target), max_piece_len);
+            n_rounds += 1;
+            if pruned.len() == pieces.len() {
+                break log_likelihood;
+            }
+            pieces = pruned;
+        };
+
+        pieces.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.text.cmp(&b.text))
+        });
+        self.pieces = specials;
+        self.pieces.extend(pieces.into_iter().map(|piece| Piece {
+            text: piece.text,
+            score: piece.score,
+            special: false,
+        }));
+        self.piece_to_id = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| !piece.special)
+            .map(|(id, piece)| (piece.text.clone(), id as u32))
+            .collect();
+        self.max_piece_len = self
+            .piece_to_id
+            .keys()
+            .map(|text| text.chars().count())
+            .max()
+            .unwrap_or(0);
+
+        UnigramReport {
+            vocab_size: self.pieces.len(),
+            n_rounds,
+            log_likelihood,
+        }
+    }
+
+    /// Encodes `input` into the most probable sequence of pieces.
+    ///
+    /// No special tokens are allowed in the input, see [`Unigram::encode_with_special`].
+    ///
+    /// # Errors
+    /// Returns an error if the input contains a character the model has no piece for, or the text
+    /// of a special token.
+    pub fn encode(&self, input: &str) -> Result<Vec<u32>, EncodingError> {
+        self.encode_with_special(input, &AllowedSpecial::None)
+    }
+
+    /// Encodes `input`, turning the literal text of allowed special tokens into their ids.
+    ///
+    /// # Errors
+    /// Returns an error if the input contains a character the model has no piece for, or the text
+    /// of a special token that is not allowed.
+    pub fn encode_with_special(
+        &self,
+        input: &str,
+        allowed: &AllowedSpecial,
+    ) -> Result<Vec<u32>, EncodingError> {
+        let mut encoded = Vec::with_capacity(input.len());
+        let specials = self.special_tokens().map(|(text, _)| text);
+        for segment in split_specials(input, specials) {
+            match segment {
+                Segment::Text(text) => {
+                    for piece in self.pre_tokenizer.split(text) {
+                        self.viterbi(piece, &mut encoded)?;
+                    }
+                }
+                Segment::Special(text) if allowed.allows(text) => {
+                    encoded.extend(self.special_token_id(text));
+                }
+                Segment::Special(text) => {
+                    return Err(EncodingError::DisallowedSpecial {
+                        text: text.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(encoded)
+    }
+
+    /// Decodes a sequence of token ids back into a string.
+    ///
+    /// # Errors
+    /// Returns an error if an unknown token id is encountered.
+    pub fn decode(&self, token_ids: &[u32]) -> Result<String, EncodingError> {
+        self.decode_with(token_ids, false)
+    }
+
+    /// Decodes a sequence of token ids back into a string, optionally leaving out special tokens.
+    ///
+    /// # Errors
+    /// Returns an error if an unknown token id is encountered.
+    pub fn decode_with(
+        &self,
+        token_ids: &[u32],
+        skip_special: bool,
+    ) -> Result<String, EncodingError> {
+        let mut decoded = String::new();
+        for &id in token_ids {
+            match self.pieces.get(id as usize) {
+                Some(piece) if piece.special && skip_special => {}
+                Some(piece) => decoded.push_str(&piece.text),
+                None => return Err(EncodingError::UnknownToken { code: id }),
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// Appends the most probable segmentation of `text` to `encoded`.
+    fn viterbi(&self, text: &str, encoded: &mut Vec<u32>) -> Result<(), EncodingError> {
+        let bounds = char_bounds(text);
+        let n = bounds.len() - 1;
+        // best score of a prefix and the last piece of its segmentation
+        let mut best = vec![(f64::NEG_INFINITY, 0, 0); n + 1];
+        best[0].0 = 0.0;
+        for end in 1..=n {
+            for start in end.saturating_sub(self.max_piece_len)..end {
+                if let Some(&id) = self.piece_to_id.get(&text[bounds[start]..bounds[end]]) {
+                    let score = best[start].0 + self.pieces[id as usize].score;
+                    if score > best[end].0 {
+                        best[end] = (score, start, id);
+                    }
+                }
+            }
+            if best[end].0 == f64::NEG_INFINITY {
+                let char = text[bounds[end - 1]..]
+                    .chars()
+                    .next()
+                    .expect("char boundary");
+                return Err(EncodingError::CharNotInVocab {
+                    char: char.to_string(),
+                    code: char as u32,
+                });
+            }
+        }
+
+        let start = encoded.len();
+        let mut end = n;
+        while end > 0 {
+            let (_, prev, id) = best[end];
+            encoded.push(id);
+            end = prev;
+        }
+        encoded[start..].reverse();
+        Ok(())
+    }
+}
+
+/// Byte offsets of the characters of `text`, followed by its length.
+fn char_bounds(text: &str) -> Vec<usize> {
+    text.char_indices()
+        .map(|(idx, _)| idx)
+        .chain([text.len()])
+        .collect()
+}
+
+/// A piece being learned, with its log probability.
+#[derive(Debug, Clone)]
+struct Candidate {
+    text: String,
+    score: f64,
+    is_char: bool,
+}
+
+/// Collects every character and the most frequent substrings of the corpus.
+fn seed_pieces(chunks: &[(&str, u64)], max_piece_len: usize, seed_size: usize) -> Vec<Candidate> {
+    let max_counted = seed_size.saturating_mul(2).max(1 << 16);
+    let mut freqs: FoldIndexMap<&str, u64> = FoldIndexMap::default();
+    let mut n_chars = 0;
+    for &(chunk, count) in chunks {
+        let bounds = char_bounds(chunk);
+        for start in 0..bounds.len() - 1 {
+            for end in start + 1..bounds.len().min(start + max_piece_len + 1) {
+                let is_char = end == start + 1;
+                *freqs
+                    .entry(&chunk[bounds[start]..bounds[end]])
+                    .or_insert_with(|| {
+                        n_chars += usize::from(is_char);
+                        0
+                    }) += count;
+            }
+        }
+        if freqs.len() - n_chars > max_counted {
+            prune_substrings(&mut freqs);
+        }
+    }
+
+    let (chars, mut substrings): (Vec<_>, Vec<_>) = freqs
+        .into_iter()
+        .partition(|(text, _)| text.chars().count() == 1);
+    substrings.retain(|&(_, freq)| freq > 1);
+    // longer substrings save more, so they rank higher at the same frequency
+    substrings.sort_by(|a, b| {
+        let a_gain = a.1 * a.0.chars().count() as u64;
+        let b_gain = b.1 * b.0.chars().count() as u64;
+        b_gain.cmp(&a_gain).then_with(|| a.0.cmp(b.0))
+    });
+    substrings.truncate(seed_size.saturating_sub(chars.len()));
+
+    let total = chars
+        .iter()
+        .chain(&substrings)
+        .map(|&(_, freq)| freq as f64)
+        .sum::<f64>()
+        .ln();
+    chars
+        .iter()
+        .map(|&(text, freq)| (text, freq, true))
+        .chain(substrings.iter().map(|&(text, freq)| (text, freq, false)))
+        .map(|(text, freq, is_char)| Candidate {
+            text: text.to_string(),
+            score: (freq as f64).ln() - total,
+            is_char,
+        })
+        .collect()
+}
+
+/// Drops the counts of the less frequent half of the substrings, keeping every character.
+fn prune_substrings(freqs: &mut FoldIndexMap<&str, u64>) {
+    let mut substring_freqs = freqs
+        .iter()
+        .filter(|(text, _)| text.chars().nth(1).is_some())
+        .map(|(_, &freq)| freq)
+        .collect::<Vec<_>>();
+    let mid = substring_freqs.len() / 2;
+    let (_, &mut median, _) = substring_freqs.select_nth_unstable(mid);
+    freqs.retain(|text, freq| *freq > median || text.chars().nth(1).is_none());
+}
+
+/// Computes the expected count of every piece over all segmentations of the corpus.
+///
+/// Returns the counts along with the log-likelihood of the corpus.
+fn expectation(
+    chunks: &[(&str, u64)],
+    pieces: &[Candidate],
+    max_piece_len: usize,
+) -> (Vec<f64>, f64) {
+    let lookup: FoldIndexMap<&str, usize> = pieces
+        .iter()
+        .enumerate()
+        .map(|(idx, piece)| (piece.text.as_str(), idx))
+        .collect();
+    let mut counts = vec![0.0; pieces.len()];
+    let mut log_likelihood = 0.0;
+
+    for &(chunk, count) in chunks {
+        let bounds = char_bounds(chunk);
+        let n = bounds.len() - 1;
+        let mut edges = Vec::new();
+        for end in 1..=n {
+            for start in end.saturating_sub(max_piece_len)..end {
+                if let Some(&idx) = lookup.get(&chunk[bounds[start]..bounds[end]]) {
+                    edges.push((start, end, idx));
+                }
+            }
+        }
+
+        // forward-backward over the lattice of pieces, in log space
+        let mut alpha = vec![f64::NEG_INFINITY; n + 1];
+        alpha[0] = 0.0;
+        for &(start, end, idx) in &edges {
+            alpha[end] = log_add(alpha[end], alpha[start] + pieces[idx].score);
+        }
+        let mut beta = vec![f64::NEG_INFINITY; n + 1];
+        beta[n] = 0.0;
+        for &(start, end, idx) in edges.iter().rev() {
+            beta[start] = log_add(beta[start], beta[end] + pieces[idx].score);
+        }
+
+        let z = alpha[n];
+        log_likelihood += count as f64 * z;
+        for &(start, end, idx) in &edges {
+            let posterior = (alpha[start] + pieces[idx].score + beta[end] - z).exp();
+            counts[idx] += count as f64 * posterior;
+        }
+    }
+
+    (counts, log_likelihood)
+}
+
+/// Turns expected counts into log probabilities, dropping pieces that are barely used.
+fn maximization(pieces: Vec<Candidate>, counts: &[f64]) -> Vec<Candidate> {
+    let kept = pieces
+        .into_iter()
+        .zip(counts)
+        .filter(|(piece, count)| piece.is_char || **count >= 0.5)
+        // characters stay reachable even when longer pieces cover them everywhere
+        .map(|(piece, &count)| (piece, count.max(0.5)))
+        .collect::<Vec<_>>();
+    let total = kept.iter().map(|(_, count)| count).sum::<f64>().ln();
+    kept.into_iter()
+        .map(|(piece, count)| Candidate {
+            score: count.ln() - total,
+            ..piece
+        })
+        .collect()
+}
+
+/// Keeps the characters and the `size` pieces whose removal would hurt the likelihood the most.
+fn prune(
+    pieces: Vec<Candidate>,
+    counts: &[f64],
+    size: usize,
+    max_piece_len: usize,
+) -> Vec<Candidate> {
+    let lookup: FoldIndexMap<&str, usize> = pieces
+        .iter()
+        .enumerate()
+        .map(|(idx, piece)| (piece.text.as_str(), idx))
+        .collect();
+
+    let mut losses = Vec::new();
+    for (idx, piece) in pieces.iter().enumerate() {
+        if piece.is_char {
+            continue;
+        }
+        // the best way to spell the piece with the other pieces
+        let bounds = char_bounds(&piece.text);
+        let n = bounds.len() - 1;
+        let mut best = vec![f64::NEG_INFINITY; n + 1];
+        best[0] = 0.0;
+        for end in 1..=n {
+            for start in end.saturating_sub(max_piece_len)..end {
+                if start == 0 && end == n {
+                    continue;
+                }
+                if let Some(&other) = lookup.get(&piece.text[bounds[start]..bounds[end]]) {
+                    best[end] = best[end].max(best[start] + pieces[other].score);
+                }
+            }
+        }
+        losses.push((counts[idx] * (piece.score - best[n]), idx));
+    }
+
+    let n_chars = pieces.len() - losses.len();
+    losses.sort_by(|a, b| {
+        b.0.total_cmp(&a.0)
+            .then_with(|| pieces[a.1].text.cmp(&pieces[b.1].text))
+    });
+    losses.truncate(size.saturating_sub(n_chars));
+    let mut keep = vec![false; pieces.len()];
+    for (_, idx) in losses {
+        keep[idx] = true;
+    }
+    pieces
+        .into_iter()
+        .zip(keep)
+        .filter(|(piece, keep)| piece.is_char || *keep)
+        .map(|(piece, _)| piece)
+        .collect()
+}
+
+fn log_add(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    if b == f64::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learns_frequent_words_as_pieces() {
+        let mut corpus = Corpus::with_special_tokens(PreTokenizer::Whitespace, ["<eos>"]);
+        for _ in 0..20 {
+            corpus.add_text("the cat sat on the mat<eos>");
+        }
+        corpus.add_text("a rat ate the hat");
+
+        let mut model = Unigram::new();
+        let report = model.learn_corpus(&corpus, &UnigramConfig::new(24));
+        assert_eq!(report.vocab_size, model.len());
+        assert!(model.len() <= 24);
+        assert_eq!(model.special_token_id("<eos>"), Some(0));
+
+        let text = "the cat sat on the hat<eos>";
+        let encoded = model
+            .encode_with_special(text, &AllowedSpecial::All)
+            .unwrap();
+        assert_eq!(model.decode(&encoded).unwrap(), text);
+        assert_eq!(
+            model.decode_with(&encoded, true).unwrap(),
+            "the cat sat on the hat"
+        );
+        let the = model.encode("the").unwrap();
+        assert_eq!(the.len(), 1);
+        assert!(model.encode("dog").is_err());
+        assert!(model.encode(text).is_err());
+    }
+
+    #[test]
+    fn learning_is_reproducible() {
+        let mut corpus = Corpus::with_pre_tokenizer(PreTokenizer::Whitespace);
+        corpus.add_text("naïve cafés serve crème brûlée, façade über alles; zoë's jalapeño piñata");
+        let config = UnigramConfig::new(30);
+
+        let mut first = Unigram::new();
+        first.learn_corpus(&corpus, &config);
+        for _ in 0..4 {
+            let mut model = Unigram::new();
+            model.learn_corpus(&corpus, &config);
+            assert_eq!(model.pieces, first.pieces);
+        }
+    }
+
+    #[test]
+    fn pruning_substrings_keeps_characters_and_frequent_substrings() {
+        let mut freqs = FoldIndexMap::default();
+        freqs.extend([
+            ("a", 1),
+            ("ab", 1),
+            ("b", 1),
+            ("abc", 3),
+            ("bc", 2),
+            ("c", 5),
+        ]);
+        prune_substrings(&mut freqs);
+        assert_eq!(
+            freqs.into_iter().collect::<Vec<_>>(),
+            [("a", 1), ("b", 1), ("abc", 3), ("c", 5)]
+        );
+    }
+}
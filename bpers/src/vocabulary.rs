@@ -1,10 +1,52 @@
+use std::io::{self, BufRead};
+
 use bincode::{Decode, Encode};
 use foldhash::{HashMap, HashMapExt};
-use indexmap::IndexMap;
+use thiserror::Error;
+
+use crate::{
+    CategorySet, CharCategory, CheckpointConfig, CheckpointError, Corpus, Lonely, MergeConstraint,
+    MergeEvent, Pair, PreTokenizer, Special, StopReason, Token, TrainConfig, TrainObserver,
+    TrainReport,
+    bpe::merge_tokens,
+    checkpoint::Checkpointer,
+    decode_bytes,
+    special::{Segment, split_specials},
+    trainer::Trainer,
+};
+
+#[derive(Error, Debug)]
+pub enum TrainError {
+    #[error("The corpus is split with another pre-tokenizer than the merges were learned with")]
+    PreTokenizerMismatch,
+    #[error(transparent)]
+    Checkpoint(#[from] CheckpointError),
+}
 
-use crate::{Lonely, Pair, Token};
+/// The base symbols `Lonely` tokens stand for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub enum Alphabet {
+    /// Unicode scalar values of the characters seen while learning.
+    #[default]
+    Chars,
+    /// All 256 byte values, so any input can be encoded.
+    Bytes,
+}
 
-type FoldIndexMap<K, V> = IndexMap<K, V, foldhash::fast::FixedState>;
+/// Statistics of a merge, recorded when it was learned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct MergeStats {
+    /// The merged pair.
+    pub pair: Pair,
+    /// The id of the merged token.
+    pub id: u32,
+    /// The zero-based position of the merge among all merges of the vocabulary.
+    pub rank: u32,
+    /// The frequency of the pair when it was merged.
+    pub frequency: u64,
+    /// The length of the tokenized corpus right after the merge.
+    pub sequence_len: u64,
+}
 
 #[derive(Debug, Encode, Decode)]
 pub struct Vocabulary {
@@ -12,6 +54,78 @@ pub struct Vocabulary {
     pub id_to_token: HashMap<u32, Token>,
     pub token_pair_to_id: HashMap<Pair, u32>,
     next_token_id: u32,
+    pre_tokenizer: PreTokenizer,
+    alphabet: Alphabet,
+    /// Texts of the special tokens by their ids.
+    special_tokens: HashMap<u32, String>,
+    /// Ids of the characters whose code point was already taken when they were added.
+    remapped_chars: HashMap<u32, u32>,
+    /// Statistics of every merge, in the order they were learned.
+    merges: Vec<MergeStats>,
+    /// The number of base symbols every token expands to, by id.
+    token_lens: HashMap<u32, u32>,
+    /// Rules the merges of the vocabulary respect.
+    constraints: Vec<MergeConstraint>,
+}
+
+impl Default for Vocabulary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The layout vocabularies were saved in before saved files were versioned: the tokens and the
+/// merge rules only.
+#[derive(Decode)]
+struct LegacyVocabulary {
+    id_to_token: HashMap<u32, Token>,
+    token_pair_to_id: HashMap<Pair, u32>,
+    next_token_id: u32,
+}
+
+impl From<LegacyVocabulary> for Vocabulary {
+    fn from(legacy: LegacyVocabulary) -> Self {
+        let mut token_lens = legacy
+            .id_to_token
+            .iter()
+            .filter(|(_, token)| matches!(token, Token::Lonely(_)))
+            .map(|(&id, _)| (id, 1))
+            .collect::<HashMap<_, _>>();
+        // merged tokens got increasing ids, so the ids give the ranks of the merges
+        let mut pairs = legacy
+            .token_pair_to_id
+            .iter()
+            .map(|(&pair, &id)| (id, pair))
+            .collect::<Vec<_>>();
+        pairs.sort_unstable();
+        let merges = pairs
+            .into_iter()
+            .enumerate()
+            .map(|(rank, (id, pair))| {
+                let len = [pair.left, pair.right]
+                    .iter()
+                    .map(|part| token_lens.get(part).copied().unwrap_or(1))
+                    .sum();
+                token_lens.insert(id, len);
+                MergeStats {
+                    pair,
+                    id,
+                    rank: rank as u32,
+                    frequency: 0,
+                    sequence_len: 0,
+                }
+            })
+            .collect();
+
+        Self {
+            id_to_token: legacy.id_to_token,
+            token_pair_to_id: legacy.token_pair_to_id,
+            next_token_id: legacy.next_token_id,
+            merges,
+            token_lens,
+            ..Self::new()
+        }
+    }
 }
 
 impl Vocabulary {
@@ -21,11 +135,192 @@ impl Vocabulary {
             id_to_token: HashMap::new(),
             token_pair_to_id: HashMap::new(),
             next_token_id: 0,
+            pre_tokenizer: PreTokenizer::None,
+            alphabet: Alphabet::Chars,
+            special_tokens: HashMap::new(),
+            remapped_chars: HashMap::new(),
+            merges: Vec::new(),
+            token_lens: HashMap::new(),
+            constraints: Vec::new(),
+        }
+    }
+
+    /// Creates a new `Vocabulary` splitting text with `pre_tokenizer` before merging.
+    pub fn with_pre_tokenizer(pre_tokenizer: PreTokenizer) -> Self {
+        Self {
+            pre_tokenizer,
+            ..Self::new()
+        }
+    }
+
+    /// Creates a new byte-level `Vocabulary` splitting text with `pre_tokenizer` before merging.
+    ///
+    /// The vocabulary starts with a `Lonely` token for each of the 256 byte values, which keep
+    /// their value as an id. Merged tokens get ids starting from 256.
+    pub fn byte_level(pre_tokenizer: PreTokenizer) -> Self {
+        let mut id_to_token = HashMap::with_capacity(256);
+        let mut token_lens = HashMap::with_capacity(256);
+        for byte in 0..=u8::MAX as u32 {
+            id_to_token.insert(byte, Lonely::new(byte).as_token());
+            token_lens.insert(byte, 1);
+        }
+        Self {
+            id_to_token,
+            token_lens,
+            next_token_id: u8::MAX as u32 + 1,
+            pre_tokenizer,
+            alphabet: Alphabet::Bytes,
+            ..Self::new()
+        }
+    }
+
+    /// Decodes a vocabulary saved before saved files were versioned, which holds only the tokens
+    /// and the merge rules.
+    ///
+    /// The vocabulary splits no text before merging. Its merges keep their ranks, but their
+    /// frequencies and sequence lengths were not recorded and are 0.
+    ///
+    /// # Errors
+    /// Returns an error if `bytes` do not hold a vocabulary of that format.
+    pub fn decode_legacy(bytes: &[u8]) -> Result<Self, bincode::error::DecodeError> {
+        let (legacy, _): (LegacyVocabulary, _) =
+            bincode::decode_from_slice(bytes, bincode::config::standard())?;
+        Ok(legacy.into())
+    }
+
+    /// Returns the base symbols of the vocabulary.
+    pub fn alphabet(&self) -> Alphabet {
+        self.alphabet
+    }
+
+    /// Returns the pre-tokenizer applied to text when learning and encoding.
+    pub fn pre_tokenizer(&self) -> &PreTokenizer {
+        &self.pre_tokenizer
+    }
+
+    /// Returns the statistics of every learned merge, ordered by rank.
+    pub fn merges(&self) -> &[MergeStats] {
+        &self.merges
+    }
+
+    /// Returns the statistics of the merge that created the token with the given `id`.
+    pub fn merge_stats(&self, id: u32) -> Option<&MergeStats> {
+        // ids of merged tokens grow with their rank
+        self.merges
+            .binary_search_by_key(&id, |stats| stats.id)
+            .ok()
+            .map(|idx| &self.merges[idx])
+    }
+
+    /// Returns the number of base symbols, characters or bytes, the token with the given `id`
+    /// expands to.
+    ///
+    /// Special tokens count the symbols of their text.
+    pub fn token_len(&self, id: u32) -> Option<usize> {
+        self.token_lens.get(&id).map(|&len| len as usize)
+    }
+
+    /// Returns the constraints every merge of the vocabulary respects.
+    ///
+    /// Constraints of a [`TrainConfig`] are added to the vocabulary when learning, and keep
+    /// applying when learning continues.
+    pub fn constraints(&self) -> &[MergeConstraint] {
+        &self.constraints
+    }
+
+    /// Returns the character categories the token with the given `id` expands to.
+    ///
+    /// Special tokens have no categories.
+    pub fn token_categories(&self, id: u32) -> Option<CategorySet> {
+        let mut categories = CategorySet::default();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            match self.id_to_token.get(&id)? {
+                Token::Lonely(lonely) => {
+                    let category = match self.alphabet {
+                        Alphabet::Chars => char::from_u32(lonely.0).map(CharCategory::of),
+                        Alphabet::Bytes => u8::try_from(lonely.0).ok().map(CharCategory::of_byte),
+                    };
+                    categories = categories.with(category.unwrap_or(CharCategory::Other));
+                }
+                Token::Pair(pair) => stack.extend([pair.left, pair.right]),
+                Token::Special(_) => {}
+            }
         }
+        Some(categories)
+    }
+
+    /// Returns `true` if merging `pair` respects the constraints of the vocabulary.
+    pub fn allows_merge(&self, pair: Pair) -> bool {
+        if self.constraints.is_empty() {
+            return true;
+        }
+        match (
+            self.token_categories(pair.left),
+            self.token_categories(pair.right),
+        ) {
+            (Some(left), Some(right)) => self
+                .constraints
+                .iter()
+                .all(|constraint| constraint.allows(left, right)),
+            _ => false,
+        }
+    }
+
+    /// Reserves a special token for `text` and returns its id.
+    ///
+    /// Special tokens are never merged with anything. Learning treats their literal text as a
+    /// boundary, and encoding may turn it into the special token, see
+    /// [`encode_with_special`](crate::encode_with_special). Registering the same text again
+    /// returns the existing id.
+    ///
+    /// Special tokens take the next free ids. A character whose code point is taken by a special
+    /// token registered before learning gets another id.
+    ///
+    /// # Panics
+    /// Panics if `text` is empty.
+    pub fn add_special_token(&mut self, text: &str) -> u32 {
+        assert!(!text.is_empty(), "special token text must not be empty");
+        if let Some(id) = self.special_token_id(text) {
+            return id;
+        }
+        let id = self.next_token_id;
+        self.id_to_token.insert(id, Special::new(id).as_token());
+        self.special_tokens.insert(id, text.to_string());
+        let len = match self.alphabet {
+            Alphabet::Chars => text.chars().count(),
+            Alphabet::Bytes => text.len(),
+        };
+        self.token_lens.insert(id, len as u32);
+        self.next_token_id += 1;
+        id
+    }
+
+    /// Returns the id of the special token with the given `text`.
+    pub fn special_token_id(&self, text: &str) -> Option<u32> {
+        self.special_tokens
+            .iter()
+            .find_map(|(&id, special)| (special == text).then_some(id))
+    }
+
+    /// Returns the text of the special token with the given `id`.
+    pub fn special_token_text(&self, id: u32) -> Option<&str> {
+        self.special_tokens.get(&id).map(String::as_str)
+    }
+
+    /// Iterates over the texts and ids of the special tokens, in no particular order.
+    pub fn special_tokens(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.special_tokens
+            .iter()
+            .map(|(&id, text)| (text.as_str(), id))
     }
 
     /// Learns vocabulary from a given corpus.
     ///
+    /// Learning can be continued on a vocabulary that has learned before, for example one loaded
+    /// from disk. Existing ids stay the same, new characters get ids that are not taken yet and new
+    /// merges are appended after the existing ones.
+    ///
     /// # Arguments
     /// * `corpus` - The input text corpus.
     /// * `n_merges` - The max number of merges to perform.
@@ -33,68 +328,406 @@ impl Vocabulary {
     /// # Returns
     /// An artifact of the learning process. Basically, it returns a byte pair encoded `corpus`.
     pub fn learn(&mut self, corpus: &str, n_merges: u32) -> Vec<u32> {
-        let mut max_char = 0;
-        let mut tokens: Vec<u32> = corpus
-            .chars()
-            .map(|char| {
-                let char_u32 = char as u32;
-                if char_u32 > max_char {
-                    max_char = char_u32;
+        self.learn_with(corpus, &TrainConfig::new(n_merges))
+    }
+
+    /// Learns vocabulary from a given corpus using the provided `config`.
+    ///
+    /// # Arguments
+    /// * `corpus` - The input text corpus.
+    /// * `config` - Parameters of the learning run.
+    ///
+    /// # Returns
+    /// An artifact of the learning process. Basically, it returns a byte pair encoded `corpus`.
+    /// Special tokens in the `corpus` are encoded as such.
+    pub fn learn_with(&mut self, corpus: &str, config: &TrainConfig) -> Vec<u32> {
+        let mut text_corpus = Corpus::for_vocabulary(self);
+        text_corpus.add_text(corpus);
+        let (trainer, _) = self
+            .train(&text_corpus, config, &mut (), None)
+            .expect("the corpus is split like the vocabulary and has no checkpoints");
+        let segments = trainer.into_segments();
+
+        let mut tokens = Vec::new();
+        for segment in self.split_specials(corpus) {
+            match segment {
+                Segment::Text(text) => {
+                    for piece in self.pre_tokenizer.split(text) {
+                        let idx = text_corpus
+                            .index_of(piece)
+                            .expect("every piece is in the corpus");
+                        tokens.extend_from_slice(&segments[idx]);
+                    }
                 }
-                char_u32
-            })
-            .collect();
-        if self.next_token_id == 0 {
-            self.next_token_id = max_char + 1;
+                Segment::Special(text) => tokens.extend(self.special_token_id(text)),
+            }
         }
+        tokens
+    }
 
-        for token in &tokens {
-            if !self.id_to_token.contains_key(token) {
-                let lonely = Lonely::new(*token).as_token();
-                self.id_to_token.insert(*token, lonely);
+    /// Learns vocabulary from a collection of documents using the provided `config`.
+    ///
+    /// No pair is ever counted or merged across the boundary of two documents.
+    ///
+    /// # Arguments
+    /// * `documents` - The documents of the text corpus.
+    /// * `config` - Parameters of the learning run.
+    ///
+    /// # Returns
+    /// A summary of the run, telling why it stopped.
+    pub fn learn_documents<D: AsRef<str>>(
+        &mut self,
+        documents: impl IntoIterator<Item = D>,
+        config: &TrainConfig,
+    ) -> TrainReport {
+        let mut corpus = Corpus::for_vocabulary(self);
+        for document in documents {
+            corpus.add_text(document.as_ref());
+        }
+        self.learn_corpus(&corpus, config)
+            .expect("the corpus is split like the vocabulary")
+    }
+
+    /// Learns vocabulary from an aggregated corpus using the provided `config`.
+    ///
+    /// Every chunk of the corpus is trained on as a separate piece of text weighted by its count,
+    /// so no merge ever spans two chunks. The pre-tokenizer of the `corpus` becomes the
+    /// pre-tokenizer of the vocabulary, and its special tokens are added to the vocabulary. A
+    /// vocabulary that has merges already keeps its pre-tokenizer, so the `corpus` must be split
+    /// with the same one, e.g. by creating it with [`Corpus::for_vocabulary`].
+    ///
+    /// Learning takes 30 to 50 bytes of memory per symbol of the distinct chunks, growing as merges
+    /// create new pairs. Without a pre-tokenizer, nothing repeats, so that is per symbol of the
+    /// whole corpus.
+    ///
+    /// # Arguments
+    /// * `corpus` - The aggregated text corpus.
+    /// * `config` - Parameters of the learning run.
+    ///
+    /// # Returns
+    /// A summary of the run, telling why it stopped.
+    ///
+    /// # Errors
+    /// Returns an error if the vocabulary has merges and the `corpus` is split with another
+    /// pre-tokenizer.
+    pub fn learn_corpus(
+        &mut self,
+        corpus: &Corpus,
+        config: &TrainConfig,
+    ) -> Result<TrainReport, TrainError> {
+        self.learn_corpus_observed(corpus, config, &mut ())
+    }
+
+    /// Learns vocabulary from an aggregated corpus, reporting every merge to `observer`.
+    ///
+    /// Works like [`Vocabulary::learn_corpus`]. The `observer` may stop learning early, in which
+    /// case the vocabulary keeps the merges performed so far and the run reports
+    /// [`StopReason::Cancelled`].
+    ///
+    /// # Arguments
+    /// * `corpus` - The aggregated text corpus.
+    /// * `config` - Parameters of the learning run.
+    /// * `observer` - Called after every merge.
+    ///
+    /// # Returns
+    /// A summary of the run, telling why it stopped.
+    ///
+    /// # Errors
+    /// Returns an error if the vocabulary has merges and the `corpus` is split with another
+    /// pre-tokenizer.
+    pub fn learn_corpus_observed(
+        &mut self,
+        corpus: &Corpus,
+        config: &TrainConfig,
+        observer: &mut impl TrainObserver,
+    ) -> Result<TrainReport, TrainError> {
+        let (_, report) = self.train(corpus, config, observer, None)?;
+        Ok(report)
+    }
+
+    /// Learns vocabulary from an aggregated corpus, saving checkpoints along the way.
+    ///
+    /// Works like [`Vocabulary::learn_corpus_observed`]. A run that gets interrupted can be
+    /// continued from its last checkpoint with [`Checkpoint::resume`](crate::Checkpoint::resume).
+    ///
+    /// # Arguments
+    /// * `corpus` - The aggregated text corpus.
+    /// * `config` - Parameters of the learning run.
+    /// * `checkpoints` - Where and how often to save checkpoints.
+    /// * `observer` - Called after every merge.
+    ///
+    /// # Returns
+    /// A summary of the run, telling why it stopped.
+    ///
+    /// # Errors
+    /// Returns an error if the vocabulary has merges and the `corpus` is split with another
+    /// pre-tokenizer, or if saving a checkpoint fails.
+    pub fn learn_corpus_checkpointed(
+        &mut self,
+        corpus: &Corpus,
+        config: &TrainConfig,
+        checkpoints: &CheckpointConfig,
+        observer: &mut impl TrainObserver,
+    ) -> Result<TrainReport, TrainError> {
+        let mut checkpointer = Checkpointer::new(checkpoints, 0);
+        let (_, report) = self.train(corpus, config, observer, Some(&mut checkpointer))?;
+        Ok(report)
+    }
+
+    /// Learns vocabulary from one or more readers without loading them into memory at once.
+    ///
+    /// The readers are streamed line by line into a [`Corpus`] split with the pre-tokenizer of
+    /// the vocabulary, so memory usage is bounded by the number of distinct pieces rather than by
+    /// the size of the input.
+    ///
+    /// # Arguments
+    /// * `readers` - Sources of the text corpus.
+    /// * `config` - Parameters of the learning run.
+    ///
+    /// # Returns
+    /// A summary of the run, telling why it stopped.
+    ///
+    /// # Errors
+    /// Returns an error if reading from any of the `readers` fails.
+    pub fn learn_from_readers<R: BufRead>(
+        &mut self,
+        readers: impl IntoIterator<Item = R>,
+        config: &TrainConfig,
+    ) -> io::Result<TrainReport> {
+        let mut corpus = Corpus::for_vocabulary(self);
+        for reader in readers {
+            corpus.read_from(reader)?;
+        }
+        Ok(self
+            .learn_corpus(&corpus, config)
+            .expect("the corpus is split like the vocabulary"))
+    }
+
+    /// Merges the most frequent pairs of the `corpus` and returns the state of the training.
+    ///
+    /// A vocabulary that has learned before keeps all its ids. The `corpus` is encoded with the
+    /// existing merges first, so new merges are appended after them.
+    fn train(
+        &mut self,
+        corpus: &Corpus,
+        config: &TrainConfig,
+        observer: &mut impl TrainObserver,
+        checkpointer: Option<&mut Checkpointer>,
+    ) -> Result<(Trainer, TrainReport), TrainError> {
+        // the existing merges only hold for text split like they were learned
+        if self.merges.is_empty() {
+            self.pre_tokenizer = corpus.pre_tokenizer().clone();
+        } else if self.pre_tokenizer != *corpus.pre_tokenizer() {
+            return Err(TrainError::PreTokenizerMismatch);
+        }
+        for text in corpus.special_tokens() {
+            self.add_special_token(text);
+        }
+        if self.alphabet == Alphabet::Chars {
+            self.add_lonely_tokens(corpus.iter().flat_map(|(chunk, _)| chunk.chars()));
+        }
+
+        let segments = corpus
+            .iter()
+            .map(|(chunk, count)| (merge_tokens(self.symbols(chunk), self), count));
+        let mut trainer = Trainer::new(segments, config.n_threads, config.pair_counting);
+        let report = self.perform_merges(&mut trainer, config, 0, observer, checkpointer)?;
+        Ok((trainer, report))
+    }
+
+    /// Splits `text` into the base symbols of the alphabet.
+    ///
+    /// Characters missing from the vocabulary keep their code point as an id.
+    pub(crate) fn symbols(&self, text: &str) -> Vec<u32> {
+        match self.alphabet {
+            Alphabet::Chars => text
+                .chars()
+                .map(|char| self.char_id_or_code(char))
+                .collect(),
+            Alphabet::Bytes => text.bytes().map(u32::from).collect(),
+        }
+    }
+
+    /// Returns the id of the `Lonely` token for `char`, if the vocabulary has one.
+    pub(crate) fn char_id(&self, char: char) -> Option<u32> {
+        let id = self.char_id_or_code(char);
+        match self.id_to_token.get(&id) {
+            Some(Token::Lonely(lonely)) if lonely.0 == char as u32 => Some(id),
+            _ => None,
+        }
+    }
+
+    fn char_id_or_code(&self, char: char) -> u32 {
+        let code = char as u32;
+        self.remapped_chars.get(&code).copied().unwrap_or(code)
+    }
+
+    /// Splits `text` at the literal text of the special tokens.
+    pub(crate) fn split_specials<'a>(&self, text: &'a str) -> Vec<Segment<'a>> {
+        split_specials(text, self.special_tokens.values().map(String::as_str))
+    }
+
+    /// Adds a `Lonely` token for every character not in the vocabulary yet.
+    ///
+    /// A character uses its code point as an id unless the id is taken, in which case it gets the
+    /// next free id instead.
+    fn add_lonely_tokens(&mut self, chars: impl Iterator<Item = char>) {
+        for char in chars {
+            if self.char_id(char).is_some() {
+                continue;
+            }
+            let char_u32 = char as u32;
+            if self.id_to_token.contains_key(&char_u32) {
+                self.id_to_token
+                    .insert(self.next_token_id, Lonely::new(char_u32).as_token());
+                self.token_lens.insert(self.next_token_id, 1);
+                self.remapped_chars.insert(char_u32, self.next_token_id);
+                self.next_token_id += 1;
+            } else {
+                self.id_to_token
+                    .insert(char_u32, Lonely::new(char_u32).as_token());
+                self.token_lens.insert(char_u32, 1);
+                self.next_token_id = self.next_token_id.max(char_u32 + 1);
             }
         }
+    }
 
-        for _ in 0..n_merges {
-            // index map for deterministic ordering
-            let mut adjacent_pair_freq: FoldIndexMap<Pair, usize> = FoldIndexMap::default();
-            for window in tokens.windows(2) {
-                let token_pair = Pair::new(window[0], window[1]);
-                *adjacent_pair_freq.entry(token_pair).or_insert(0) += 1;
+    /// Merges the most frequent pairs of the `trainer` until `config` says to stop.
+    ///
+    /// The forced tokens of `config` are merged first, regardless of when the run stops otherwise.
+    /// The run continues after `n_merges` merges performed earlier, e.g. before a checkpoint.
+    pub(crate) fn perform_merges(
+        &mut self,
+        trainer: &mut Trainer,
+        config: &TrainConfig,
+        mut n_merges: u32,
+        observer: &mut impl TrainObserver,
+        mut checkpointer: Option<&mut Checkpointer>,
+    ) -> Result<TrainReport, CheckpointError> {
+        for constraint in &config.constraints {
+            if !self.constraints.contains(constraint) {
+                self.constraints.push(*constraint);
             }
+        }
+        if self.alphabet == Alphabet::Chars {
+            self.add_lonely_tokens(config.forced_tokens.iter().flat_map(|text| text.chars()));
+        }
 
-            match adjacent_pair_freq.into_iter().max_by_key(|(_, freq)| *freq) {
-                Some((most_freq_pair, pair_freq)) if pair_freq > 1 => {
-                    self.id_to_token
-                        .insert(self.next_token_id, most_freq_pair.as_token());
-                    self.token_pair_to_id
-                        .insert(most_freq_pair, self.next_token_id);
+        // forced texts yet to become a single token, the next one last
+        let mut forced_tokens = config.forced_tokens.iter().rev().collect::<Vec<_>>();
+        let stop_reason = loop {
+            let (most_freq_pair, pair_freq) = match self.next_forced_pair(&mut forced_tokens) {
+                Some(pair) => (pair, None),
+                None => {
+                    if n_merges >= config.n_merges {
+                        break StopReason::MaxMerges;
+                    }
+                    if config
+                        .vocab_size
+                        .is_some_and(|vocab_size| self.id_to_token.len() >= vocab_size)
+                    {
+                        break StopReason::VocabSizeReached;
+                    }
 
-                    let mut updated_tokens = Vec::with_capacity(tokens.len());
-                    let mut i = 0;
-                    while i < tokens.len() {
-                        if i + 1 < tokens.len()
-                            && tokens[i] == most_freq_pair.left
-                            && tokens[i + 1] == most_freq_pair.right
-                        {
-                            updated_tokens.push(self.next_token_id);
-                            i += 2;
-                        } else {
-                            updated_tokens.push(tokens[i]);
-                            i += 1;
+                    // pairs that may not be merged never may, so they are dropped for good
+                    let best = loop {
+                        match trainer.pop_best() {
+                            Some((pair, _)) if !self.may_merge(pair, config) => continue,
+                            best => break best,
                         }
+                    };
+                    let Some((most_freq_pair, pair_freq)) = best else {
+                        break StopReason::NoFrequentPairs;
+                    };
+                    if pair_freq < config.min_frequency.max(1) {
+                        break StopReason::NoFrequentPairs;
                     }
-
-                    tokens = updated_tokens;
-                    self.next_token_id += 1;
-                }
-                _ => {
-                    break;
+                    if config.min_gain.is_some_and(|min_gain| {
+                        (pair_freq as f64 / trainer.sequence_len() as f64) < min_gain
+                    }) {
+                        break StopReason::LowGain;
+                    }
+                    (most_freq_pair, Some(pair_freq))
                 }
+            };
+
+            self.id_to_token
+                .insert(self.next_token_id, most_freq_pair.as_token());
+            self.token_pair_to_id
+                .insert(most_freq_pair, self.next_token_id);
+            let len =
+                self.token_lens[&most_freq_pair.left] + self.token_lens[&most_freq_pair.right];
+            self.token_lens.insert(self.next_token_id, len);
+
+            let merged = trainer.merge(most_freq_pair, self.next_token_id);
+            // forced pairs were not counted, so count what got replaced
+            let pair_freq = pair_freq.unwrap_or(merged);
+            self.merges.push(MergeStats {
+                pair: most_freq_pair,
+                id: self.next_token_id,
+                rank: self.merges.len() as u32,
+                frequency: pair_freq,
+                sequence_len: trainer.sequence_len(),
+            });
+            let event = MergeEvent {
+                index: n_merges,
+                pair: most_freq_pair,
+                id: self.next_token_id,
+                frequency: pair_freq,
+                sequence_len: trainer.sequence_len(),
+            };
+            self.next_token_id += 1;
+            n_merges += 1;
+
+            if let Some(checkpointer) = checkpointer.as_deref_mut() {
+                checkpointer.save_if_due(self, trainer, n_merges)?;
             }
+            if observer.on_merge(&event).is_break() {
+                break StopReason::Cancelled;
+            }
+        };
+
+        Ok(TrainReport {
+            n_merges,
+            vocab_size: self.id_to_token.len(),
+            stop_reason,
+        })
+    }
+
+    /// Returns the next pair to merge so that every text of `forced_tokens` becomes a token.
+    ///
+    /// A forced text is encoded with the current merges, then its tokens are merged from left to
+    /// right. Encoding the text later on takes the same merges, so it becomes a single token.
+    /// Texts that already encode to a single token are popped from the end of `forced_tokens`.
+    fn next_forced_pair(&self, forced_tokens: &mut Vec<&String>) -> Option<Pair> {
+        while let Some(text) = forced_tokens.last() {
+            let tokens = merge_tokens(self.symbols(text), self);
+            if tokens.len() > 1 {
+                return Some(Pair::new(tokens[0], tokens[1]));
+            }
+            forced_tokens.pop();
         }
+        None
+    }
 
-        tokens
+    /// Returns `true` if merging `pair` respects the max token length and forbidden merges of
+    /// `config`, and the constraints of the vocabulary.
+    fn may_merge(&self, pair: Pair, config: &TrainConfig) -> bool {
+        let fits = config.max_token_len.is_none_or(|max_len| {
+            let len = self.token_lens[&pair.left] + self.token_lens[&pair.right];
+            len as usize <= max_len
+        });
+        let forbidden = !config.forbidden_merges.is_empty() && {
+            let left = decode_bytes(&[pair.left], self).unwrap_or_default();
+            let right = decode_bytes(&[pair.right], self).unwrap_or_default();
+            config
+                .forbidden_merges
+                .iter()
+                .any(|(forbidden_left, forbidden_right)| {
+                    forbidden_left.as_bytes() == left && forbidden_right.as_bytes() == right
+                })
+        };
+        fits && !forbidden && self.allows_merge(pair)
     }
 }
 
@@ -127,4 +760,541 @@ mod tests {
         // 3. XdXac (len 5)
         assert_eq!(tokenized.len(), 5)
     }
+
+    /// The straightforward trainer that recounts every pair on each merge.
+    fn learn_naive(corpus: &str, n_merges: u32) -> (Vec<Pair>, Vec<u32>) {
+        type FoldIndexMap<K, V> = indexmap::IndexMap<K, V, foldhash::fast::FixedState>;
+
+        let mut tokens: Vec<u32> = corpus.chars().map(|c| c as u32).collect();
+        let mut next_token_id = tokens.iter().max().map_or(1, |max| max + 1);
+        let mut merges = Vec::new();
+
+        for _ in 0..n_merges {
+            let mut adjacent_pair_freq: FoldIndexMap<Pair, usize> = FoldIndexMap::default();
+            for window in tokens.windows(2) {
+                *adjacent_pair_freq
+                    .entry(Pair::new(window[0], window[1]))
+                    .or_insert(0) += 1;
+            }
+
+            match adjacent_pair_freq.into_iter().max_by_key(|(_, freq)| *freq) {
+                Some((pair, freq)) if freq > 1 => {
+                    let mut updated_tokens = Vec::with_capacity(tokens.len());
+                    let mut i = 0;
+                    while i < tokens.len() {
+                        if i + 1 < tokens.len()
+                            && tokens[i] == pair.left
+                            && tokens[i + 1] == pair.right
+                        {
+                            updated_tokens.push(next_token_id);
+                            i += 2;
+                        } else {
+                            updated_tokens.push(tokens[i]);
+                            i += 1;
+                        }
+                    }
+                    tokens = updated_tokens;
+                    merges.push(pair);
+                    next_token_id += 1;
+                }
+                _ => break,
+            }
+        }
+
+        (merges, tokens)
+    }
+
+    #[test]
+    fn incremental_learn_matches_naive() {
+        let corpora = [
+            "aaabdaaabac",
+            "aaaaaaaaaaaaaaaaa",
+            "abababababab aaaa bbbb abab",
+            "the quick brown fox jumps over the lazy dog, then the dog sleeps",
+            "  ====  ====    ==== x ==== ",
+            "",
+        ];
+
+        for corpus in corpora {
+            let (expected_merges, expected_tokens) = learn_naive(corpus, 50);
+
+            let mut vocabulary = Vocabulary::new();
+            let tokens = vocabulary.learn(corpus, 50);
+
+            let mut merges = vocabulary.token_pair_to_id.iter().collect::<Vec<_>>();
+            merges.sort_by_key(|(_, id)| **id);
+            let merges = merges
+                .into_iter()
+                .map(|(pair, _)| *pair)
+                .collect::<Vec<_>>();
+
+            assert_eq!(merges, expected_merges, "merges differ for {corpus:?}");
+            assert_eq!(tokens, expected_tokens, "tokens differ for {corpus:?}");
+        }
+    }
+
+    /// The straightforward encoder that rescans every pair on each merge.
+    fn merge_naive(mut tokens: Vec<u32>, vocabulary: &Vocabulary) -> Vec<u32> {
+        loop {
+            let best = tokens
+                .windows(2)
+                .filter_map(|window| {
+                    let pair = Pair::new(window[0], window[1]);
+                    vocabulary.token_pair_to_id.get(&pair).map(|&id| (id, pair))
+                })
+                .min();
+            let Some((merged_id, pair)) = best else {
+                return tokens;
+            };
+
+            let mut updated_tokens = Vec::with_capacity(tokens.len());
+            let mut i = 0;
+            while i < tokens.len() {
+                if i + 1 < tokens.len() && tokens[i] == pair.left && tokens[i + 1] == pair.right {
+                    updated_tokens.push(merged_id);
+                    i += 2;
+                } else {
+                    updated_tokens.push(tokens[i]);
+                    i += 1;
+                }
+            }
+            tokens = updated_tokens;
+        }
+    }
+
+    #[test]
+    fn encode_matches_naive_merging() {
+        let corpora = [
+            "aaabdaaabac",
+            "aaaaaaaaaaaaaaaaa",
+            "abababababab aaaa bbbb abab",
+            "the quick brown fox jumps over the lazy dog, then the dog sleeps",
+        ];
+
+        for corpus in corpora {
+            let mut vocabulary = Vocabulary::new();
+            vocabulary.learn(corpus, 50);
+            let reversed = corpus.chars().rev().collect::<String>();
+            for input in [corpus, &reversed, &corpus.repeat(3), &corpus[3..]] {
+                let symbols = input.chars().map(|char| char as u32).collect::<Vec<_>>();
+                assert_eq!(
+                    crate::encode(input, &vocabulary).unwrap(),
+                    merge_naive(symbols, &vocabulary),
+                    "encodings differ for {input:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_learn_matches_single_threaded() {
+        let corpus = "abababab aaaa bbbb aaaaaaa the cat sat on the mat, aaaa abab thethe";
+
+        let mut expected = Vocabulary::new();
+        let expected_tokens = expected.learn(corpus, 40);
+
+        for n_threads in [2, 3, 7, 64, 1000] {
+            let config = TrainConfig {
+                n_threads,
+                ..TrainConfig::new(40)
+            };
+            let mut vocabulary = Vocabulary::new();
+            let tokens = vocabulary.learn_with(corpus, &config);
+
+            assert_eq!(tokens, expected_tokens, "{n_threads} threads");
+            assert_eq!(vocabulary.id_to_token, expected.id_to_token);
+            assert_eq!(vocabulary.token_pair_to_id, expected.token_pair_to_id);
+        }
+    }
+
+    #[test]
+    fn learn_from_readers_never_merges_across_lines() {
+        let input = "ab\nab\nab\n";
+
+        for n_threads in [1, 4] {
+            let config = TrainConfig {
+                n_threads,
+                ..TrainConfig::new(10)
+            };
+            let mut vocabulary = Vocabulary::new();
+            vocabulary
+                .learn_from_readers([input.as_bytes()], &config)
+                .unwrap();
+
+            // every line collapses into a single token, nothing joins "\n" with the next line
+            assert_eq!(vocabulary.token_pair_to_id.len(), 2);
+            assert!(
+                vocabulary
+                    .token_pair_to_id
+                    .keys()
+                    .all(|pair| pair.left != '\n' as u32)
+            );
+        }
+    }
+
+    #[test]
+    fn pre_tokenized_learn_and_encode_agree() {
+        let corpus = "low lower lowest newer newest wider widest";
+        let mut vocabulary = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        let tokens = vocabulary.learn(corpus, 30);
+
+        let space = ' ' as u32;
+        assert!(
+            vocabulary
+                .token_pair_to_id
+                .keys()
+                .all(|pair| pair.left != space && pair.right != space)
+        );
+        assert_eq!(crate::encode(corpus, &vocabulary).unwrap(), tokens);
+    }
+
+    #[test]
+    fn byte_level_encodes_anything() {
+        let mut vocabulary = Vocabulary::byte_level(PreTokenizer::None);
+        let tokens = vocabulary.learn("żółw żółw żółw", 5);
+
+        assert_eq!(vocabulary.id_to_token.len(), 256 + 5);
+        assert_eq!(
+            crate::encode("żółw żółw żółw", &vocabulary).unwrap(),
+            tokens
+        );
+
+        let input = b"\xff\xfe \xc5\xbc\xc3\xb3\xc5\x82w";
+        let encoded = crate::encode_bytes(input, &vocabulary).unwrap();
+        assert!(encoded.len() < input.len());
+        assert_eq!(crate::decode_bytes(&encoded, &vocabulary).unwrap(), input);
+        assert!(crate::decode(&encoded, &vocabulary).is_err());
+    }
+
+    #[test]
+    fn learn_stops_at_vocab_size() {
+        let mut corpus = Corpus::new();
+        corpus.add_text("aaabdaaabac");
+
+        let mut vocabulary = Vocabulary::new();
+        let report = vocabulary
+            .learn_corpus(&corpus, &TrainConfig::with_vocab_size(6))
+            .unwrap();
+        assert_eq!(report.stop_reason, StopReason::VocabSizeReached);
+        assert_eq!(report.n_merges, 2);
+        assert_eq!(vocabulary.id_to_token.len(), 6);
+
+        let mut vocabulary = Vocabulary::new();
+        let report = vocabulary
+            .learn_corpus(&corpus, &TrainConfig::with_vocab_size(100))
+            .unwrap();
+        assert_eq!(report.stop_reason, StopReason::NoFrequentPairs);
+        assert_eq!(report.vocab_size, 7);
+    }
+
+    #[test]
+    fn learn_respects_min_frequency_and_gain() {
+        let mut corpus = Corpus::new();
+        corpus.add_text("aaabdaaabac");
+
+        // "aa" occurs 4 times, "ab" 2 times after that
+        let mut vocabulary = Vocabulary::new();
+        let config = TrainConfig {
+            min_frequency: 3,
+            ..TrainConfig::new(10)
+        };
+        let report = vocabulary.learn_corpus(&corpus, &config).unwrap();
+        assert_eq!(report.stop_reason, StopReason::NoFrequentPairs);
+        assert_eq!(report.n_merges, 1);
+
+        // the gain of "aa" is 4/11, the gain of "ab" is 2/9 after that
+        let mut vocabulary = Vocabulary::new();
+        let config = TrainConfig {
+            min_gain: Some(0.3),
+            ..TrainConfig::new(10)
+        };
+        let report = vocabulary.learn_corpus(&corpus, &config).unwrap();
+        assert_eq!(report.stop_reason, StopReason::LowGain);
+        assert_eq!(report.n_merges, 1);
+    }
+
+    #[test]
+    fn special_tokens_are_reserved_and_never_merged() {
+        let mut vocabulary = Vocabulary::new();
+        let eot = vocabulary.add_special_token("<|endoftext|>");
+        let pad = vocabulary.add_special_token("\u{1}");
+        assert_eq!((eot, pad), (0, 1));
+
+        let corpus = "ab<|endoftext|>ab<|endoftext|>\u{1}ab\u{1}";
+        let tokens = vocabulary.learn(corpus, 10);
+        assert_eq!(vocabulary.add_special_token("<|endoftext|>"), eot);
+        assert_eq!(vocabulary.token_pair_to_id.len(), 1);
+        assert!(
+            vocabulary
+                .token_pair_to_id
+                .keys()
+                .all(|pair| ![eot, pad].contains(&pair.left) && ![eot, pad].contains(&pair.right))
+        );
+
+        let all = crate::AllowedSpecial::All;
+        assert_eq!(
+            crate::encode_with_special(corpus, &vocabulary, &all).unwrap(),
+            tokens
+        );
+        assert!(matches!(
+            crate::encode(corpus, &vocabulary),
+            Err(crate::EncodingError::DisallowedSpecial { .. })
+        ));
+        assert_eq!(crate::decode(&tokens, &vocabulary).unwrap(), corpus);
+        assert_eq!(
+            crate::decode_with(&tokens, &vocabulary, true).unwrap(),
+            "ababab"
+        );
+
+        // the code point of U+0001 is taken by a special token, so the character gets another id
+        let mut corpus = Corpus::new();
+        corpus.add_text("\u{1}");
+        vocabulary
+            .learn_corpus(&corpus, &TrainConfig::new(0))
+            .unwrap();
+        let tokens = crate::encode_ordinary("\u{1}", &vocabulary).unwrap();
+        assert_ne!(tokens, [pad]);
+        assert_eq!(crate::decode(&tokens, &vocabulary).unwrap(), "\u{1}");
+    }
+
+    #[test]
+    fn continued_learn_keeps_ids_and_appends_merges() {
+        let corpus = "aaabdaaabac";
+        let mut uninterrupted = Vocabulary::new();
+        uninterrupted.learn(corpus, 3);
+
+        let mut continued = Vocabulary::new();
+        continued.learn(corpus, 1);
+        continued.learn(corpus, 2);
+        assert_eq!(continued.id_to_token, uninterrupted.id_to_token);
+        assert_eq!(continued.token_pair_to_id, uninterrupted.token_pair_to_id);
+
+        // "ab" takes the id 99, which is the code point of 'c'
+        let mut vocabulary = Vocabulary::new();
+        vocabulary.learn("abab", 1);
+        let ab = vocabulary.token_pair_to_id[&Pair::new('a' as u32, 'b' as u32)];
+        assert_eq!(ab, 'c' as u32);
+
+        vocabulary.learn("abcabc", 1);
+        assert_eq!(
+            vocabulary.id_to_token[&ab],
+            Pair::new('a' as u32, 'b' as u32).as_token()
+        );
+        assert_eq!(vocabulary.token_pair_to_id.len(), 2);
+        let encoded = crate::encode("abcab", &vocabulary).unwrap();
+        assert_eq!(encoded.len(), 2);
+        assert_eq!(crate::decode(&encoded, &vocabulary).unwrap(), "abcab");
+    }
+
+    #[test]
+    fn observer_sees_every_merge_and_can_cancel() {
+        let mut corpus = Corpus::new();
+        corpus.add_text("aaabdaaabac");
+
+        let mut events = Vec::new();
+        let mut vocabulary = Vocabulary::new();
+        let report = vocabulary
+            .learn_corpus_observed(&corpus, &TrainConfig::new(10), &mut |event: &MergeEvent| {
+                events.push(*event);
+                if event.index == 1 {
+                    std::ops::ControlFlow::Break(())
+                } else {
+                    std::ops::ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+
+        assert_eq!(report.stop_reason, StopReason::Cancelled);
+        assert_eq!(report.n_merges, 2);
+        assert_eq!(vocabulary.token_pair_to_id.len(), 2);
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| (event.frequency, event.sequence_len))
+                .collect::<Vec<_>>(),
+            [(4, 9), (2, 7)]
+        );
+        assert_eq!(vocabulary.token_pair_to_id[&events[1].pair], events[1].id);
+        assert!(crate::encode("aaabdaaabac", &vocabulary).is_ok());
+    }
+
+    #[test]
+    fn learn_documents_never_merges_across_documents() {
+        let mut vocabulary = Vocabulary::new();
+        let report = vocabulary.learn_documents(["xa", "by", "xa", "by"], &TrainConfig::new(10));
+        assert_eq!(report.n_merges, 2);
+        assert!(
+            !vocabulary
+                .token_pair_to_id
+                .contains_key(&Pair::new('a' as u32, 'b' as u32))
+        );
+    }
+
+    #[test]
+    fn non_overlapping_counting_does_not_inflate_runs() {
+        let mut corpus = Corpus::new();
+        corpus.add_count("aaaaaa", 1);
+        corpus.add_count("xy", 4);
+
+        // (a, a) occurs 5 times, but only 3 of them can be merged
+        let mut vocabulary = Vocabulary::new();
+        vocabulary
+            .learn_corpus(&corpus, &TrainConfig::new(1))
+            .unwrap();
+        assert!(
+            vocabulary
+                .token_pair_to_id
+                .contains_key(&Pair::new('a' as u32, 'a' as u32))
+        );
+
+        let config = TrainConfig {
+            pair_counting: crate::PairCounting::NonOverlapping,
+            ..TrainConfig::new(2)
+        };
+        let mut vocabulary = Vocabulary::new();
+        let mut merges = Vec::new();
+        vocabulary
+            .learn_corpus_observed(&corpus, &config, &mut |event: &MergeEvent| {
+                merges.push((event.pair, event.frequency));
+                std::ops::ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(
+            merges,
+            [
+                (Pair::new('x' as u32, 'y' as u32), 4),
+                (Pair::new('a' as u32, 'a' as u32), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_record_their_statistics() {
+        let mut vocabulary = Vocabulary::new();
+        vocabulary.learn("aaabdaaabac", 2);
+        vocabulary.learn("aaabdaaabac", 1);
+
+        let stats = vocabulary
+            .merges()
+            .iter()
+            .map(|stats| (stats.rank, stats.frequency, stats.sequence_len))
+            .collect::<Vec<_>>();
+        assert_eq!(stats, [(0, 4, 9), (1, 2, 7), (2, 2, 5)]);
+        for stats in vocabulary.merges() {
+            assert_eq!(vocabulary.token_pair_to_id[&stats.pair], stats.id);
+            assert_eq!(vocabulary.merge_stats(stats.id), Some(stats));
+        }
+        assert_eq!(vocabulary.merge_stats('a' as u32), None);
+    }
+
+    #[test]
+    fn max_token_len_limits_merged_tokens() {
+        let mut corpus = Corpus::new();
+        corpus.add_count("boilerplate", 10);
+        let config = TrainConfig {
+            max_token_len: Some(3),
+            ..TrainConfig::new(100)
+        };
+
+        let mut vocabulary = Vocabulary::new();
+        let report = vocabulary.learn_corpus(&corpus, &config).unwrap();
+        assert_eq!(report.stop_reason, StopReason::NoFrequentPairs);
+        assert!(report.n_merges > 0);
+        for stats in vocabulary.merges() {
+            assert!(vocabulary.token_len(stats.id).unwrap() <= 3);
+        }
+        assert_eq!(vocabulary.token_len('b' as u32), Some(1));
+        let encoded = crate::encode("boilerplate", &vocabulary).unwrap();
+        assert_eq!(encoded.len(), 4);
+    }
+
+    #[test]
+    fn forced_tokens_are_merged_and_forbidden_merges_skipped() {
+        let mut corpus = Corpus::new();
+        corpus.add_count("impl abab", 10);
+        corpus.add_count("fn x", 1);
+        let config = TrainConfig {
+            forced_tokens: vec!["impl".to_string(), "fn q".to_string()],
+            forbidden_merges: vec![("a".to_string(), "b".to_string())],
+            ..TrainConfig::new(8)
+        };
+
+        let mut vocabulary = Vocabulary::new();
+        let report = vocabulary.learn_corpus(&corpus, &config).unwrap();
+        // 3 merges per forced text, then 2 learned ones
+        assert_eq!(report.n_merges, 8);
+        assert_eq!(report.stop_reason, StopReason::MaxMerges);
+        assert_eq!(vocabulary.merges()[0].frequency, 10);
+        assert_eq!(crate::encode("impl", &vocabulary).unwrap().len(), 1);
+        assert_eq!(crate::encode("fn q", &vocabulary).unwrap().len(), 1);
+        assert!(
+            !vocabulary
+                .token_pair_to_id
+                .contains_key(&Pair::new('a' as u32, 'b' as u32))
+        );
+    }
+
+    #[test]
+    fn forced_tokens_take_precedence_over_merge_rules() {
+        let mut corpus = Corpus::new();
+        corpus.add_count("impl", 10);
+        let config = TrainConfig {
+            max_token_len: Some(2),
+            forced_tokens: vec!["impl".to_string()],
+            forbidden_merges: vec![("i".to_string(), "m".to_string())],
+            ..TrainConfig::new(0)
+        };
+
+        let mut vocabulary = Vocabulary::new();
+        let report = vocabulary.learn_corpus(&corpus, &config).unwrap();
+        assert_eq!(report.n_merges, 3);
+        assert_eq!(report.stop_reason, StopReason::MaxMerges);
+        assert_eq!(crate::encode("impl", &vocabulary).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn decodes_legacy_vocabularies() {
+        // learned from "aaabdaaabac" with 3 merges, before saved files were versioned
+        let bytes = [
+            0x07, 0x61, 0x00, 0x61, 0x62, 0x00, 0x62, 0x66, 0x01, 0x61, 0x62, 0x63, 0x00, 0x63,
+            0x65, 0x01, 0x61, 0x61, 0x67, 0x01, 0x65, 0x66, 0x64, 0x00, 0x64, 0x03, 0x61, 0x61,
+            0x65, 0x61, 0x62, 0x66, 0x65, 0x66, 0x67, 0x68,
+        ];
+        let mut vocabulary = Vocabulary::decode_legacy(&bytes).unwrap();
+        assert_eq!(vocabulary.id_to_token.len(), 7);
+        assert_eq!(vocabulary.merges().len(), 3);
+        assert_eq!(vocabulary.token_len(103), Some(4));
+        assert_eq!(
+            crate::encode("aaabdaaabac", &vocabulary).unwrap(),
+            [103, 100, 103, 97, 99]
+        );
+
+        // learning continues after the legacy merges
+        vocabulary.learn("dadada", 1);
+        let stats = vocabulary.merges().last().unwrap();
+        assert_eq!((stats.id, stats.rank), (104, 3));
+    }
+
+    #[test]
+    fn continued_learning_keeps_the_pre_tokenizer() {
+        let mut vocabulary = Vocabulary::with_pre_tokenizer(PreTokenizer::Whitespace);
+        vocabulary.learn("the cat sat on the mat", 3);
+        let n_merges = vocabulary.merges().len();
+
+        let mut corpus = Corpus::with_pre_tokenizer(PreTokenizer::None);
+        corpus.add_text("the hat");
+        assert!(matches!(
+            vocabulary.learn_corpus(&corpus, &TrainConfig::new(3)),
+            Err(TrainError::PreTokenizerMismatch)
+        ));
+        assert_eq!(vocabulary.pre_tokenizer(), &PreTokenizer::Whitespace);
+        assert_eq!(vocabulary.merges().len(), n_merges);
+
+        let mut corpus = Corpus::for_vocabulary(&vocabulary);
+        corpus.add_text("the hat the hat");
+        vocabulary
+            .learn_corpus(&corpus, &TrainConfig::new(3))
+            .unwrap();
+        assert!(vocabulary.merges().len() > n_merges);
+    }
 }
@@ -0,0 +1,568 @@
+use std::collections::BTreeSet;
+
+use bincode::{Decode, Encode};
+use foldhash::HashMap;
+
+use crate::{
+    AllowedSpecial, Corpus, EncodingError, PreTokenizer,
+    special::{Segment, split_specials},
+};
+
+/// Parameters of a WordPiece learning run.
+#[derive(Debug, Clone)]
+pub struct WordPieceConfig {
+    /// The vocabulary size to stop at, counting special tokens and the alphabet.
+    pub vocab_size: usize,
+    /// The minimum frequency of a pair to be merged.
+    pub min_frequency: u64,
+    /// The prefix marking tokens that continue a word.
+    pub continuing_prefix: String,
+    /// The special token standing in for words that cannot be encoded.
+    pub unk_token: String,
+    /// Words with more characters are encoded as the unknown token right away.
+    pub max_input_chars_per_word: usize,
+}
+
+impl WordPieceConfig {
+    /// Creates a `WordPieceConfig` learning up to `vocab_size` tokens, using the BERT prefix and
+    /// unknown token.
+    pub fn new(vocab_size: usize) -> Self {
+        Self {
+            vocab_size,
+            min_frequency: 2,
+            continuing_prefix: "##".to_string(),
+            unk_token: "[UNK]".to_string(),
+            max_input_chars_per_word: 100,
+        }
+    }
+}
+
+/// Summary of a WordPiece learning run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WordPieceReport {
+    /// The number of merges performed.
+    pub n_merges: u32,
+    /// The size of the vocabulary after the run.
+    pub vocab_size: usize,
+}
+
+/// A WordPiece tokenizer, as used by BERT.
+///
+/// Tokens that continue a word are marked with a prefix, `##` by default. Every piece of the
+/// pre-tokenizer is encoded greedily, taking the longest matching token first, and becomes the
+/// unknown token as a whole if it cannot be matched.
+#[derive(Debug, Default, Encode, Decode)]
+pub struct WordPiece {
+    /// Token texts by their ids, including the continuing prefix.
+    tokens: Vec<String>,
+    /// Ids of the tokens starting a word, by their text.
+    token_to_id: HashMap<String, u32>,
+    /// Ids of the tokens continuing a word, by their text without the continuing prefix.
+    continuing_to_id: HashMap<String, u32>,
+    /// Ids of the special tokens.
+    special_tokens: HashMap<String, u32>,
+    continuing_prefix: String,
+    unk_token: String,
+    max_input_chars_per_word: usize,
+    pre_tokenizer: PreTokenizer,
+}
+
+impl WordPiece {
+    /// Creates a new empty `WordPiece` model.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new empty `WordPiece` model splitting text with `pre_tokenizer`.
+    pub fn with_pre_tokenizer(pre_tokenizer: PreTokenizer) -> Self {
+        Self {
+            pre_tokenizer,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the pre-tokenizer applied to text when learning and encoding.
+    pub fn pre_tokenizer(&self) -> &PreTokenizer {
+        &self.pre_tokenizer
+    }
+
+    /// Returns the token texts, indexed by id.
+    pub fn tokens(&self) -> &[String] {
+        &self.tokens
+    }
+
+    /// Returns the number of tokens, counting special tokens.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Returns `true` if the model has no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Returns the id of the token with the given `text`, continuing prefix included.
+    ///
+    /// A text starting with the continuing prefix is looked up among the tokens continuing a word
+    /// first.
+    pub fn token_id(&self, text: &str) -> Option<u32> {
+        text.strip_prefix(self.continuing_prefix.as_str())
+            .and_then(|text| self.continuing_to_id.get(text))
+            .or_else(|| self.token_to_id.get(text))
+            .copied()
+    }
+
+    /// Returns the id of the unknown token, once the model has been learned.
+    pub fn unk_token_id(&self) -> Option<u32> {
+        self.special_token_id(&self.unk_token)
+    }
+
+    /// Reserves a special token for `text` and returns its id.
+    ///
+    /// Registering the same text again returns the existing id.
+    ///
+    /// # Panics
+    /// Panics if `text` is empty.
+    pub fn add_special_token(&mut self, text: &str) -> u32 {
+        assert!(!text.is_empty(), "special token text must not be empty");
+        if let Some(id) = self.special_token_id(text) {
+            return id;
+        }
+        let id = self.tokens.len() as u32;
+        self.tokens.push(text.to_string());
+        self.special_tokens.insert(text.to_string(), id);
+        id
+    }
+
+    /// Returns the id of the special token with the given `text`.
+    pub fn special_token_id(&self, text: &str) -> Option<u32> {
+        self.special_tokens.get(text).copied()
+    }
+
+    /// Iterates over the texts and ids of the special tokens.
+    pub fn special_tokens(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.special_tokens
+            .iter()
+            .map(|(text, &id)| (text.as_str(), id))
+    }
+
+    /// Learns the tokens of the model from an aggregated corpus.
+    ///
+    /// Starts from the characters of the corpus and repeatedly merges the pair with the highest
+    /// score, its frequency divided by the frequencies of its parts, so pairs whose parts rarely
+    /// occur apart are merged first. Previously learned tokens are replaced; special tokens are
+    /// kept and take the first ids, the unknown token among them.
+    ///
+    /// The pre-tokenizer of the `corpus` becomes the pre-tokenizer of the model, and its special
+    /// tokens are added to the model.
+    ///
+    /// # Arguments
+    /// * `corpus` - The aggregated text corpus.
+    /// * `config` - Parameters of the learning run.
+    ///
+    /// # Returns
+    /// A summary of the run.
+    pub fn learn_corpus(&mut self, corpus: &Corpus, config: &WordPieceConfig) -> WordPieceReport {
+        self.pre_tokenizer = corpus.pre_tokenizer().clone();
+        self.continuing_prefix = config.continuing_prefix.clone();
+        self.unk_token = config.unk_token.clone();
+        self.max_input_chars_per_word = config.max_input_chars_per_word;
+
+        let specials = self
+            .special_tokens()
+            .map(|(text, id)| (id, text.to_string()));
+        let mut specials = specials.collect::<Vec<_>>();
+        specials.sort();
+        self.tokens.clear();
+        self.token_to_id.clear();
+        self.continuing_to_id.clear();
+        self.special_tokens.clear();
+        self.add_special_token(&config.unk_token);
+        for (_, text) in specials {
+            self.add_special_token(&text);
+        }
+        for text in corpus.special_tokens() {
+            self.add_special_token(text);
+        }
+
+        let alphabet = corpus
+            .iter()
+            .flat_map(|(chunk, _)| {
+                chunk
+                    .chars()
+                    .enumerate()
+                    .map(|(idx, char)| (idx > 0, char.to_string()))
+            })
+            .collect::<BTreeSet<_>>();
+        for (continuing, text) in alphabet {
+            self.push_token(&text, continuing);
+        }
+        let mut words = corpus
+            .iter()
+            .map(|(chunk, count)| {
+                let symbols = chunk.chars().enumerate().map(|(idx, char)| {
+                    let ids = match idx {
+                        0 => &self.token_to_id,
+                        _ => &self.continuing_to_id,
+                    };
+                    ids[char.encode_utf8(&mut [0; 4]) as &str]
+                });
+                (symbols.collect::<Vec<_>>(), count)
+            })
+            .collect::<Vec<_>>();
+
+        let mut counts = PairCounts::new(&words);
+        let mut n_merges = 0;
+        while self.tokens.len() < config.vocab_size {
+            let Some((left, right)) = counts.best_pair(config.min_frequency) else {
+                break;
+            };
+            let (left_text, continuing) = self.piece(left);
+            let text = format!("{left_text}{}", self.piece(right).0);
+            let ids = match continuing {
+                false => &self.token_to_id,
+                true => &self.continuing_to_id,
+            };
+            // the same text can be reached by merging different pairs
+            let id = match ids.get(&text) {
+                Some(&id) => id,
+                None => self.push_token(&text, continuing),
+            };
+            counts.merge(&mut words, (left, right), id);
+            n_merges += 1;
+        }
+
+        WordPieceReport {
+            n_merges,
+            vocab_size: self.tokens.len(),
+        }
+    }
+
+    /// Encodes `input` into a sequence of token ids.
+    ///
+    /// No special tokens are allowed in the input, see [`WordPiece::encode_with_special`].
+    ///
+    /// # Errors
+    /// Returns an error if the input contains the text of a special token.
+    pub fn encode(&self, input: &str) -> Result<Vec<u32>, EncodingError> {
+        self.encode_with_special(input, &AllowedSpecial::None)
+    }
+
+    /// Encodes `input`, turning the literal text of allowed special tokens into their ids.
+    ///
+    /// Pieces that cannot be matched become the unknown token.
+    ///
+    /// # Errors
+    /// Returns an error if the input contains the text of a special token that is not allowed,
+    /// or if it cannot be encoded by a model that has not been learned.
+    pub fn encode_with_special(
+        &self,
+        input: &str,
+        allowed: &AllowedSpecial,
+    ) -> Result<Vec<u32>, EncodingError> {
+        let mut encoded = Vec::with_capacity(input.len());
+        let specials = self.special_tokens().map(|(text, _)| text);
+        for segment in split_specials(input, specials) {
+            match segment {
+                Segment::Text(text) => {
+                    for word in self.pre_tokenizer.split(text) {
+                        self.encode_word(word, &mut encoded)?;
+                    }
+                }
+                Segment::Special(text) if allowed.allows(text) => {
+                    encoded.extend(self.special_token_id(text));
+                }
+                Segment::Special(text) => {
+                    return Err(EncodingError::DisallowedSpecial {
+                        text: text.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(encoded)
+    }
+
+    /// Decodes a sequence of token ids back into a string.
+    ///
+    /// The continuing prefix is left out of tokens continuing a word, so text without unknown
+    /// words decodes to the input.
+    ///
+    /// # Errors
+    /// Returns an error if an unknown token id is encountered.
+    pub fn decode(&self, token_ids: &[u32]) -> Result<String, EncodingError> {
+        self.decode_with(token_ids, false)
+    }
+
+    /// Decodes a sequence of token ids back into a string, optionally leaving out special tokens.
+    ///
+    /// # Errors
+    /// Returns an error if an unknown token id is encountered.
+    pub fn decode_with(
+        &self,
+        token_ids: &[u32],
+        skip_special: bool,
+    ) -> Result<String, EncodingError> {
+        let mut decoded = String::new();
+        for &id in token_ids {
+            let Some(text) = self.tokens.get(id as usize) else {
+                return Err(EncodingError::UnknownToken { code: id });
+            };
+            if self.special_tokens.contains_key(text) {
+                if !skip_special {
+                    decoded.push_str(text);
+                }
+            } else {
+                decoded.push_str(self.piece(id).0);
+            }
+        }
+        Ok(decoded)
+    }
+
+    fn push_token(&mut self, text: &str, continuing: bool) -> u32 {
+        let id = self.tokens.len() as u32;
+        if continuing {
+            self.continuing_to_id.insert(text.to_string(), id);
+            self.tokens
+                .push(format!("{}{text}", self.continuing_prefix));
+        } else {
+            self.token_to_id.insert(text.to_string(), id);
+            self.tokens.push(text.to_string());
+        }
+        id
+    }
+
+    /// Returns the text of a learned token without the continuing prefix, along with whether it
+    /// continues a word.
+    ///
+    /// The prefix alone does not tell, as a token starting a word may start with the prefix too.
+    fn piece(&self, id: u32) -> (&str, bool) {
+        let text = self.tokens[id as usize].as_str();
+        match text.strip_prefix(self.continuing_prefix.as_str()) {
+            Some(continued) if self.continuing_to_id.get(continued) == Some(&id) => {
+                (continued, true)
+            }
+            _ => (text, false),
+        }
+    }
+
+    /// Appends the tokens of `word` to `encoded`, longest match first.
+    fn encode_word(&self, word: &str, encoded: &mut Vec<u32>) -> Result<(), EncodingError> {
+        let unk = || {
+            self.unk_token_id().ok_or_else(|| {
+                let char = word.chars().next().expect("pieces are not empty");
+                EncodingError::CharNotInVocab {
+                    char: char.to_string(),
+                    code: char as u32,
+                }
+            })
+        };
+        if word.chars().count() > self.max_input_chars_per_word {
+            encoded.push(unk()?);
+            return Ok(());
+        }
+
+        let start_len = encoded.len();
+        let mut start = 0;
+        while start < word.len() {
+            let ids = match start {
+                0 => &self.token_to_id,
+                _ => &self.continuing_to_id,
+            };
+            let mut end = word.len();
+            let id = loop {
+                if let Some(&id) = ids.get(&word[start..end]) {
+                    break Some(id);
+                }
+                match word[start..end].char_indices().next_back() {
+                    Some((0, _)) | None => break None,
+                    Some((idx, _)) => end = start + idx,
+                }
+            };
+            match id {
+                Some(id) => encoded.push(id),
+                None => {
+                    encoded.truncate(start_len);
+                    encoded.push(unk()?);
+                    return Ok(());
+                }
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+/// Pair and token frequencies of the words, updated as pairs are merged.
+#[derive(Debug, Default)]
+struct PairCounts {
+    pairs: HashMap<(u32, u32), u64>,
+    tokens: HashMap<u32, u64>,
+    /// The indices of the words every pair occurs in. May hold words the pair no longer occurs
+    /// in, and the same word more than once.
+    occurrences: HashMap<(u32, u32), Vec<usize>>,
+}
+
+impl PairCounts {
+    fn new(words: &[(Vec<u32>, u64)]) -> Self {
+        let mut counts = Self::default();
+        for (idx, (symbols, count)) in words.iter().enumerate() {
+            counts.add(symbols, *count);
+            for pair in symbols.windows(2) {
+                counts.add_occurrence((pair[0], pair[1]), idx);
+            }
+        }
+        counts
+    }
+
+    /// Finds the pair with the highest WordPiece score among pairs occurring at least
+    /// `min_frequency` times.
+    fn best_pair(&self, min_frequency: u64) -> Option<(u32, u32)> {
+        let score = |(left, right), freq: u64| {
+            freq as f64 / (self.tokens[&left] as f64 * self.tokens[&right] as f64)
+        };
+        self.pairs
+            .iter()
+            .map(|(&pair, &freq)| (pair, freq))
+            .filter(|&(_, freq)| freq >= min_frequency.max(1))
+            .max_by(|&(a, a_freq), &(b, b_freq)| {
+                score(a, a_freq)
+                    .total_cmp(&score(b, b_freq))
+                    .then(a_freq.cmp(&b_freq))
+                    .then(b.cmp(&a))
+            })
+            .map(|(pair, _)| pair)
+    }
+
+    /// Merges `pair` into `id` in the words it occurs in, updating the counts of only those.
+    fn merge(&mut self, words: &mut [(Vec<u32>, u64)], pair: (u32, u32), id: u32) {
+        let Some(word_indices) = self.occurrences.remove(&pair) else {
+            return;
+        };
+        for idx in word_indices {
+            let (symbols, count) = &mut words[idx];
+            if !symbols
+                .windows(2)
+                .any(|window| (window[0], window[1]) == pair)
+            {
+                continue;
+            }
+            self.remove(symbols, *count);
+            merge_pair(symbols, pair, id);
+            self.add(symbols, *count);
+            for window in symbols.windows(2) {
+                if window[0] == id || window[1] == id {
+                    self.add_occurrence((window[0], window[1]), idx);
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, symbols: &[u32], count: u64) {
+        for &symbol in symbols {
+            *self.tokens.entry(symbol).or_insert(0) += count;
+        }
+        for pair in symbols.windows(2) {
+            *self.pairs.entry((pair[0], pair[1])).or_insert(0) += count;
+        }
+    }
+
+    fn remove(&mut self, symbols: &[u32], count: u64) {
+        for &symbol in symbols {
+            decrement(&mut self.tokens, symbol, count);
+        }
+        for pair in symbols.windows(2) {
+            decrement(&mut self.pairs, (pair[0], pair[1]), count);
+        }
+    }
+
+    fn add_occurrence(&mut self, pair: (u32, u32), idx: usize) {
+        let word_indices = self.occurrences.entry(pair).or_default();
+        if word_indices.last() != Some(&idx) {
+            word_indices.push(idx);
+        }
+    }
+}
+
+/// Subtracts `count` from the frequency of `key`, dropping it once it reaches zero.
+fn decrement<K: std::hash::Hash + Eq>(freqs: &mut HashMap<K, u64>, key: K, count: u64) {
+    if let Some(freq) = freqs.get_mut(&key) {
+        *freq -= count;
+        if *freq == 0 {
+            freqs.remove(&key);
+        }
+    }
+}
+
+/// Replaces every occurrence of `pair` in `symbols` with `id`, from left to right.
+fn merge_pair(symbols: &mut Vec<u32>, pair: (u32, u32), id: u32) {
+    let mut read = 0;
+    let mut write = 0;
+    while read < symbols.len() {
+        if read + 1 < symbols.len() && (symbols[read], symbols[read + 1]) == pair {
+            symbols[write] = id;
+            read += 2;
+        } else {
+            symbols[write] = symbols[read];
+            read += 1;
+        }
+        write += 1;
+    }
+    symbols.truncate(write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_longest_match_first_with_unknown_fallback() {
+        let mut corpus = Corpus::with_pre_tokenizer(PreTokenizer::Whitespace);
+        for _ in 0..10 {
+            corpus.add_text("hugging hug hugs bun buns");
+        }
+
+        let mut model = WordPiece::new();
+        let report = model.learn_corpus(&corpus, &WordPieceConfig::new(30));
+        assert_eq!(report.vocab_size, model.len());
+        assert_eq!(model.unk_token_id(), Some(0));
+        assert!(model.token_id("##s").is_some());
+
+        let text = "hugs bun hugging";
+        let encoded = model.encode(text).unwrap();
+        assert_eq!(model.decode(&encoded).unwrap(), text);
+        assert_eq!(model.encode("hug").unwrap().len(), 1);
+        // an unseen word is spelled with continuation tokens
+        let bugs = model.encode("bugs").unwrap();
+        assert!(bugs.len() > 1);
+        assert!(
+            bugs[1..]
+                .iter()
+                .all(|&id| model.tokens()[id as usize].starts_with("##"))
+        );
+
+        // a word with an unseen character is unknown as a whole
+        let encoded = model.encode("hugx bun").unwrap();
+        assert_eq!(encoded[0], 0);
+        assert_eq!(model.decode(&encoded).unwrap(), "[UNK] bun");
+        assert_eq!(model.decode_with(&encoded, true).unwrap(), " bun");
+    }
+
+    #[test]
+    fn tokens_starting_with_the_prefix_decode_verbatim() {
+        let mut corpus = Corpus::with_pre_tokenizer(PreTokenizer::Whitespace);
+        for _ in 0..10 {
+            corpus.add_text("##tag ##tag #x");
+        }
+
+        let mut model = WordPiece::new();
+        model.learn_corpus(&corpus, &WordPieceConfig::new(30));
+        let encoded = model.encode("##tag").unwrap();
+        assert_eq!(encoded.len(), 1);
+        assert_eq!(model.tokens()[encoded[0] as usize], "##tag");
+        assert_eq!(model.decode(&encoded).unwrap(), "##tag");
+        assert_eq!(
+            model.decode(&model.encode("#x ##x").unwrap()).unwrap(),
+            "#x ##x"
+        );
+    }
+}
@@ -1,18 +1,44 @@
 use std::{
     collections::HashMap,
     fs::File,
-    io::Write,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::ControlFlow,
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, parser::ValueSource};
+use indicatif::{ProgressBar, ProgressStyle};
 
-use bpers::{self, Vocabulary};
+use bpers::{
+    self, AllowedSpecial, CharCategory, Checkpoint, CheckpointConfig, Corpus, DocumentSplit,
+    Dropout, MergeConstraint, MergeEvent, Model, PairCounting, PreTokenizer, StopReason,
+    StreamEncoder, StreamError, TrainConfig, TrainError, Unigram, UnigramConfig, Vocabulary,
+    WordPiece, WordPieceConfig,
+};
 
 const DEFAULT_N_MERGES: u32 = 2000;
+const DEFAULT_MODEL_VOCAB_SIZE: usize = 8000;
 const DEFAULT_VOCAB_OUT: &str = "vocab.bin";
 const DEFAULT_ENCODED_OUT: &str = "encoded.txt";
+/// Ids and flags of the `learn` options only BPE models support.
+const BPE_ONLY_LEARN_ARGS: &[(&str, &str)] = &[
+    ("from", "--from"),
+    ("n_merges", "--merges"),
+    ("min_gain", "--min-gain"),
+    ("non_overlapping", "--non-overlapping"),
+    ("max_token_len", "--max-token-len"),
+    ("separate", "--separate"),
+    ("isolate", "--isolate"),
+    ("forced_tokens", "--force"),
+    ("forbidden_merges", "--forbid"),
+    ("n_threads", "--threads"),
+    ("byte_level", "--byte-level"),
+    ("time_limit", "--time-limit"),
+    ("checkpoint", "--checkpoint"),
+    ("resume", "--resume"),
+];
 
 /// BPE - byte pair encoding
 #[derive(Debug, Parser)]
@@ -22,19 +48,96 @@ struct Cli {
     cmd: CliCommand,
 }
 
+// parsed once, so the size of the learn options does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 enum CliCommand {
     /// Learn a vocabulary from a corpus
     Learn {
         /// Path to an existing text file(s)
-        #[arg(required = true, num_args = 1..)]
+        #[arg(required_unless_present = "resume", num_args = 1..)]
         input: Vec<PathBuf>,
         /// Output file for vocabulary
         #[arg(short = 'o', long="out", default_value = DEFAULT_VOCAB_OUT)]
         out: PathBuf,
+        /// The kind of tokenizer to learn
+        #[arg(long = "model", value_enum, default_value_t = ModelKind::Bpe)]
+        model: ModelKind,
+        /// Continue learning from an existing vocabulary binary file
+        #[arg(long = "from", default_value = None, conflicts_with_all = ["pre_tokenizer", "split_pattern", "byte_level"])]
+        from: Option<PathBuf>,
         /// Max number of merges to perform during vocabulary learning
         #[arg(short = 'm', long = "merges", default_value_t = DEFAULT_N_MERGES)]
         n_merges: u32,
+        /// Target vocabulary size, including base symbols. Replaces --merges. Unigram and
+        /// WordPiece models default to 8000
+        #[arg(short = 's', long = "vocab-size", conflicts_with = "n_merges")]
+        vocab_size: Option<usize>,
+        /// Minimum frequency of a pair to be merged
+        #[arg(long = "min-frequency", default_value_t = 2)]
+        min_frequency: u64,
+        /// Stop when the next merge removes less than this fraction of tokens
+        #[arg(long = "min-gain", default_value = None)]
+        min_gain: Option<f64>,
+        /// Count only pair occurrences that can be merged without overlapping, e.g. 2 in "aaaa"
+        #[arg(long = "non-overlapping")]
+        non_overlapping: bool,
+        /// Max number of characters (or bytes) a merged token may expand to
+        #[arg(long = "max-token-len", default_value = None)]
+        max_token_len: Option<usize>,
+        /// Never merge characters of the two categories into one token, e.g. letter+whitespace.
+        /// Can be repeated
+        #[arg(long = "separate", value_parser = parse_category_pair)]
+        separate: Vec<(CategoryKind, CategoryKind)>,
+        /// Never merge characters of the category, e.g. digit. Can be repeated
+        #[arg(long = "isolate", value_enum)]
+        isolate: Vec<CategoryKind>,
+        /// Make the text a single token, merged before any learned pair. Can be repeated
+        #[arg(long = "force", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+        forced_tokens: Vec<String>,
+        /// Never merge a token with the text LEFT and a token with the text RIGHT. Can be repeated
+        #[arg(long = "forbid", num_args = 2, value_names = ["LEFT", "RIGHT"])]
+        forbidden_merges: Vec<String>,
+        /// Number of worker threads used for learning
+        #[arg(short = 'j', long = "threads", default_value_t = 1)]
+        n_threads: usize,
+        /// How to split text into words before learning. Defaults to none for BPE and to
+        /// whitespace for Unigram and WordPiece models. Learning BPE without one takes up to 50
+        /// bytes of memory per character of the corpus
+        #[arg(long = "pre-tokenizer", value_enum, default_value = None)]
+        pre_tokenizer: Option<PreTokenizerKind>,
+        /// A regex whose matches are used as words. Overrides --pre-tokenizer
+        #[arg(long = "split-pattern", default_value = None)]
+        split_pattern: Option<String>,
+        /// What counts as a document. Pairs are never merged across documents. Without a
+        /// pre-tokenizer, a whole file document is held in memory at once
+        #[arg(long = "documents", value_enum, default_value_t = DocumentKind::File)]
+        documents: DocumentKind,
+        /// Use the 256 byte values as base symbols instead of characters
+        #[arg(long = "byte-level")]
+        byte_level: bool,
+        /// Stop learning after this many seconds, keeping the merges learned so far
+        #[arg(long = "time-limit", default_value = None)]
+        time_limit: Option<f64>,
+        /// Periodically save the learning state to this file
+        #[arg(long = "checkpoint", default_value = None)]
+        checkpoint: Option<PathBuf>,
+        /// Save a checkpoint after this many merges
+        #[arg(long = "checkpoint-every", default_value = None, requires = "checkpoint")]
+        checkpoint_every: Option<u32>,
+        /// Save a checkpoint after this many seconds
+        #[arg(
+            long = "checkpoint-interval",
+            default_value_t = 600.0,
+            requires = "checkpoint"
+        )]
+        checkpoint_interval: f64,
+        /// Continue an interrupted run from a checkpoint. Pass the same learning options again
+        #[arg(long = "resume", default_value = None, conflicts_with_all = ["input", "from", "documents", "pre_tokenizer", "split_pattern", "byte_level", "special_tokens"])]
+        resume: Option<PathBuf>,
+        /// Reserve a special token that is never merged. Can be repeated
+        #[arg(long = "special", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+        special_tokens: Vec<String>,
     },
     /// Perform text encoding
     Encode {
@@ -50,6 +153,29 @@ enum CliCommand {
         /// Max number of merges to perform during vocabulary learning. Used when no vocabulary is provided
         #[arg(short = 'm', long = "merges", default_value_t = DEFAULT_N_MERGES)]
         n_merges: u32,
+        /// Encode the text of special tokens in the input as special tokens
+        #[arg(long = "allow-special")]
+        allow_special: bool,
+        /// Skip every applicable merge with this probability (BPE-dropout)
+        #[arg(long = "dropout", default_value = None, value_parser = parse_probability, requires = "vocabulary_path")]
+        dropout: Option<f64>,
+        /// Seed of the random number generator used by --dropout
+        #[arg(long = "seed", default_value_t = 0, requires = "dropout")]
+        seed: u64,
+        /// Encode every line of the input as a separate document, in parallel. Writes the token
+        /// ids of each document as a line of numbers. Fails without writing anything if a line
+        /// cannot be encoded
+        #[arg(
+            long = "lines",
+            requires = "vocabulary_path",
+            conflicts_with = "dropout"
+        )]
+        lines: bool,
+        /// Read and encode the input incrementally, keeping only the unencoded tail in memory.
+        /// With a --split-pattern vocabulary, input is only encoded up to the end of a line,
+        /// assuming the pattern does not look past the next line. BPE vocabularies only
+        #[arg(long = "stream", requires = "vocabulary_path", conflicts_with_all = ["dropout", "lines"])]
+        stream: bool,
     },
     /// Decode using provided vocabulary
     Decode {
@@ -61,11 +187,74 @@ enum CliCommand {
         /// Out for decoded text. Stdout if not provided
         #[arg(short = 'o', long = "out", default_value = None)]
         out: Option<PathBuf>,
+        /// Leave special tokens out of the decoded text
+        #[arg(long = "skip-special")]
+        skip_special: bool,
+        /// Decode a file written by `encode --lines`, one document per line, in parallel. Fails
+        /// without writing anything if a line cannot be parsed or decoded
+        #[arg(long = "lines")]
+        lines: bool,
     },
     /// Run example process to demonstrate BPE
     Example,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ModelKind {
+    /// Byte pair encoding
+    Bpe,
+    /// Unigram language model
+    Unigram,
+    /// WordPiece, as used by BERT
+    Wordpiece,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum PreTokenizerKind {
+    /// Do not split text
+    None,
+    /// Split into runs of whitespace and non-whitespace characters
+    Whitespace,
+    /// Split using the GPT-2 pattern
+    Gpt2,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CategoryKind {
+    /// Alphabetic characters
+    Letter,
+    /// Numeric characters
+    Digit,
+    /// Whitespace characters
+    Whitespace,
+    /// ASCII punctuation and symbols
+    Punctuation,
+    /// Any other character
+    Other,
+}
+
+impl From<CategoryKind> for CharCategory {
+    fn from(kind: CategoryKind) -> Self {
+        match kind {
+            CategoryKind::Letter => Self::Letter,
+            CategoryKind::Digit => Self::Digit,
+            CategoryKind::Whitespace => Self::Whitespace,
+            CategoryKind::Punctuation => Self::Punctuation,
+            CategoryKind::Other => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DocumentKind {
+    /// Every input file
+    File,
+    /// Every line
+    Line,
+    /// Every paragraph separated by blank lines
+    Paragraph,
+}
+
 #[derive(Debug, Clone)]
 enum PathyString {
     String(String),
@@ -90,33 +279,251 @@ impl PathyString {
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
     match cli.cmd {
         CliCommand::Learn {
             input,
             out,
+            model,
+            from,
             n_merges,
+            vocab_size,
+            min_frequency,
+            min_gain,
+            non_overlapping,
+            max_token_len,
+            separate,
+            isolate,
+            forced_tokens,
+            forbidden_merges,
+            n_threads,
+            pre_tokenizer,
+            split_pattern,
+            documents,
+            byte_level,
+            time_limit,
+            checkpoint,
+            checkpoint_every,
+            checkpoint_interval,
+            resume,
+            special_tokens,
         } => {
-            let mut vocab = Vocabulary::new();
-
-            let input = input
-                .iter()
-                .map(|path| match std::fs::read_to_string(path) {
-                    Ok(contents) => contents,
+            let pre_tokenizer = match split_pattern {
+                Some(pattern) => match PreTokenizer::regex(&pattern) {
+                    Ok(pre_tokenizer) => pre_tokenizer,
                     Err(err) => {
-                        eprintln!("Failed to load {} contents: {err}", path.display());
+                        eprintln!("{err}");
                         std::process::exit(1);
                     }
-                })
-                .collect::<Vec<String>>()
-                .join(" ");
+                },
+                None => match pre_tokenizer.unwrap_or(match model {
+                    ModelKind::Bpe => PreTokenizerKind::None,
+                    ModelKind::Unigram | ModelKind::Wordpiece => PreTokenizerKind::Whitespace,
+                }) {
+                    PreTokenizerKind::None => PreTokenizer::None,
+                    PreTokenizerKind::Whitespace => PreTokenizer::Whitespace,
+                    PreTokenizerKind::Gpt2 => PreTokenizer::Gpt2,
+                },
+            };
+            let split = match documents {
+                DocumentKind::File => DocumentSplit::File,
+                DocumentKind::Line => DocumentSplit::Line,
+                DocumentKind::Paragraph => DocumentSplit::Paragraph,
+            };
+
+            if model != ModelKind::Bpe {
+                let learn_matches = matches
+                    .subcommand_matches("learn")
+                    .expect("learn options were parsed");
+                let given =
+                    |id: &str| learn_matches.value_source(id) == Some(ValueSource::CommandLine);
+                if let Some((_, flag)) = BPE_ONLY_LEARN_ARGS.iter().find(|(id, _)| given(id)) {
+                    eprintln!("Only BPE models support {flag}");
+                    std::process::exit(1);
+                }
+                if model == ModelKind::Unigram && given("min_frequency") {
+                    eprintln!("Unigram models do not support --min-frequency");
+                    std::process::exit(1);
+                }
+                // every piece is a single word, so whole documents would become the unknown token
+                if model == ModelKind::Wordpiece && pre_tokenizer == PreTokenizer::None {
+                    eprintln!("WordPiece models need a pre-tokenizer other than none");
+                    std::process::exit(1);
+                }
+
+                println!("Reading corpus");
+                let mut corpus = Corpus::with_special_tokens(pre_tokenizer, &special_tokens);
+                for path in &input {
+                    let read = File::open(path)
+                        .and_then(|file| corpus.read_documents_from(BufReader::new(file), split));
+                    if let Err(err) = read {
+                        eprintln!("Failed to load {}: {err}", path.display());
+                        std::process::exit(1);
+                    }
+                }
+
+                println!("Learning");
+                let vocab_size = vocab_size.unwrap_or(DEFAULT_MODEL_VOCAB_SIZE);
+                let learned = match model {
+                    ModelKind::Unigram => {
+                        let mut unigram = Unigram::new();
+                        let report = unigram.learn_corpus(&corpus, &UnigramConfig::new(vocab_size));
+                        println!("\nLearned vocabulary size: {}", report.vocab_size);
+                        println!("Pruning rounds: {}", report.n_rounds);
+                        println!("Log-likelihood: {:.2}", report.log_likelihood);
+                        Model::Unigram(unigram)
+                    }
+                    ModelKind::Wordpiece => {
+                        let mut wordpiece = WordPiece::new();
+                        let config = WordPieceConfig {
+                            min_frequency,
+                            ..WordPieceConfig::new(vocab_size)
+                        };
+                        let report = wordpiece.learn_corpus(&corpus, &config);
+                        println!("\nLearned vocabulary size: {}", report.vocab_size);
+                        println!("Amount of merged tokens: {}", report.n_merges);
+                        Model::WordPiece(wordpiece)
+                    }
+                    ModelKind::Bpe => unreachable!("BPE vocabularies are learned below"),
+                };
+
+                if let Err(err) = save_model(&learned, &out) {
+                    eprintln!("Failed to save vocabulary: {err}");
+                };
+                return;
+            }
 
-            println!("Learning");
-            _ = vocab.learn(&input, n_merges);
-            println!("\nLearned vocabulary size: {}", vocab.id_to_token.len());
+            let config = TrainConfig {
+                min_frequency,
+                min_gain,
+                n_threads,
+                max_token_len,
+                constraints: separate
+                    .into_iter()
+                    .map(|(a, b)| MergeConstraint::Separate(a.into(), b.into()))
+                    .chain(
+                        isolate
+                            .into_iter()
+                            .map(|category| MergeConstraint::Isolate(category.into())),
+                    )
+                    .collect(),
+                forced_tokens,
+                forbidden_merges: forbidden_merges
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect(),
+                pair_counting: if non_overlapping {
+                    PairCounting::NonOverlapping
+                } else {
+                    PairCounting::Overlapping
+                },
+                ..match vocab_size {
+                    Some(vocab_size) => TrainConfig::with_vocab_size(vocab_size),
+                    None => TrainConfig::new(n_merges),
+                }
+            };
+            let checkpoints = checkpoint.map(|path| CheckpointConfig {
+                every_merges: checkpoint_every,
+                every: Some(Duration::from_secs_f64(checkpoint_interval)),
+                ..CheckpointConfig::new(path)
+            });
+
+            let progress = match vocab_size {
+                Some(_) => ProgressBar::new_spinner(),
+                None => ProgressBar::new(u64::from(n_merges)),
+            };
+            progress.set_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise}] {wide_bar} {pos}/{len} merges {msg}",
+                )
+                .expect("progress template is valid"),
+            );
+            let started = Instant::now();
+            let mut observer = |event: &MergeEvent| {
+                progress.set_position(u64::from(event.index) + 1);
+                progress.set_message(format!("(frequency {})", event.frequency));
+                match time_limit {
+                    Some(limit) if started.elapsed().as_secs_f64() >= limit => {
+                        ControlFlow::Break(())
+                    }
+                    _ => ControlFlow::Continue(()),
+                }
+            };
+
+            let learned = match resume {
+                Some(path) => {
+                    println!("Resuming from {}", path.display());
+                    Checkpoint::load(&path)
+                        .and_then(|checkpoint| {
+                            checkpoint.resume(&config, checkpoints.as_ref(), &mut observer)
+                        })
+                        .map_err(TrainError::from)
+                }
+                None => {
+                    let mut vocab = match from {
+                        Some(path) => match load_model(&path) {
+                            Ok(Model::Bpe(vocab)) => vocab,
+                            Ok(_) => {
+                                eprintln!("Only BPE vocabularies can be learned further");
+                                std::process::exit(1);
+                            }
+                            Err(err) => {
+                                eprintln!("Failed to load vocabulary: {err}");
+                                std::process::exit(1);
+                            }
+                        },
+                        None if byte_level => Vocabulary::byte_level(pre_tokenizer),
+                        None => Vocabulary::with_pre_tokenizer(pre_tokenizer),
+                    };
+                    for text in &special_tokens {
+                        vocab.add_special_token(text);
+                    }
+
+                    println!("Reading corpus");
+                    let mut corpus = Corpus::for_vocabulary(&vocab);
+                    for path in &input {
+                        let read = File::open(path).and_then(|file| {
+                            corpus.read_documents_from(BufReader::new(file), split)
+                        });
+                        if let Err(err) = read {
+                            eprintln!("Failed to load {}: {err}", path.display());
+                            std::process::exit(1);
+                        }
+                    }
+
+                    println!("Learning");
+                    match &checkpoints {
+                        Some(checkpoints) => vocab
+                            .learn_corpus_checkpointed(&corpus, &config, checkpoints, &mut observer)
+                            .map(|report| (vocab, report)),
+                        None => vocab
+                            .learn_corpus_observed(&corpus, &config, &mut observer)
+                            .map(|report| (vocab, report)),
+                    }
+                }
+            };
+            progress.finish();
+            let (vocab, report) = match learned {
+                Ok(learned) => learned,
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1);
+                }
+            };
+            println!("\nLearned vocabulary size: {}", report.vocab_size);
             println!("Amount of merged tokens: {}", vocab.token_pair_to_id.len());
+            match report.stop_reason {
+                StopReason::MaxMerges => println!("Stopped after {} merges", report.n_merges),
+                StopReason::VocabSizeReached => println!("Target vocabulary size reached"),
+                StopReason::NoFrequentPairs => {
+                    println!("Stopped early: no pair occurs at least {min_frequency} times")
+                }
+                StopReason::LowGain => println!("Stopped early: compression gain dropped too low"),
+                StopReason::Cancelled => println!("Stopped early: time limit reached"),
+            }
 
-            if let Err(err) = save_vocab(&vocab, &out) {
+            if let Err(err) = save_model(&Model::Bpe(vocab), &out) {
                 eprintln!("Failed to save vocabulary: {err}");
             };
         }
@@ -125,24 +532,127 @@ fn main() {
             out,
             n_merges,
             vocabulary_path,
+            allow_special,
+            dropout,
+            seed,
+            lines,
+            stream,
         } => {
-            let mut vocab = Vocabulary::new();
+            let allowed = if allow_special {
+                AllowedSpecial::All
+            } else {
+                AllowedSpecial::None
+            };
+
+            if stream {
+                let vocab = match vocabulary_path.as_deref().map(load_model) {
+                    Some(Ok(Model::Bpe(vocab))) => vocab,
+                    Some(Ok(_)) => {
+                        eprintln!("Only BPE vocabularies support --stream");
+                        std::process::exit(1);
+                    }
+                    Some(Err(err)) => {
+                        eprintln!("Failed to load vocabulary: {err}");
+                        std::process::exit(1);
+                    }
+                    None => unreachable!("--stream requires a vocabulary"),
+                };
+                let reader: Box<dyn Read> = match input {
+                    PathyString::Path(path) => match File::open(path) {
+                        Ok(file) => Box::new(BufReader::new(file)),
+                        Err(err) => {
+                            eprintln!("Failed to open input file: {err}");
+                            std::process::exit(1);
+                        }
+                    },
+                    PathyString::String(str) => Box::new(std::io::Cursor::new(str.into_bytes())),
+                };
+
+                println!("Streaming encoded data to {}", out.display());
+                let n_tokens = File::create(&out)
+                    .map_err(StreamError::from)
+                    .and_then(|file| {
+                        let mut writer = BufWriter::new(file);
+                        let stream = StreamEncoder::with_special(&vocab, allowed);
+                        let n_tokens =
+                            stream.encode(reader, |ids| write_encoded(&mut writer, ids))?;
+                        writer.flush()?;
+                        Ok(n_tokens)
+                    });
+                match n_tokens {
+                    Ok(n_tokens) => println!("\nEncoded size: {n_tokens}\n"),
+                    Err(err) => {
+                        eprintln!("Encoding failed: {err}");
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+
             let input = match input {
-                PathyString::Path(path) => match std::fs::read_to_string(path) {
+                PathyString::Path(path) => match std::fs::read(path) {
                     Ok(contents) => contents,
                     Err(err) => {
                         eprintln!("Failed to load file contents: {err}");
                         std::process::exit(1);
                     }
                 },
-                PathyString::String(str) => str,
+                PathyString::String(str) => str.into_bytes(),
             };
 
+            if lines {
+                let model = match vocabulary_path.as_deref().map(load_model) {
+                    Some(Ok(model)) => model,
+                    Some(Err(err)) => {
+                        eprintln!("Failed to load vocabulary: {err}");
+                        std::process::exit(1);
+                    }
+                    None => unreachable!("--lines requires a vocabulary"),
+                };
+
+                let documents = split_lines(&input);
+                println!("Encoding {} lines", documents.len());
+                let encoded = model
+                    .encode_bytes_batch_with_special(&documents, &allowed)
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, result)| {
+                        result
+                            .inspect_err(|err| eprintln!("Encoding line {} failed: {err}", i + 1))
+                            .ok()
+                    })
+                    .collect::<Vec<_>>();
+                if encoded.len() < documents.len() {
+                    std::process::exit(1);
+                }
+
+                if let Err(err) = save_encoded_lines(&encoded, &out) {
+                    eprintln!("Failed to save encoded data: {err}");
+                };
+                return;
+            }
+
             let encoded = match vocabulary_path {
-                Some(path) => match load_vocab(&path) {
-                    Ok(vocab) => {
+                Some(path) => match load_model(&path) {
+                    Ok(model) => {
                         println!("Encoding");
-                        match bpers::encode(&input, &vocab) {
+                        let encoded = match (&model, dropout) {
+                            (Model::Bpe(vocab), Some(probability)) => {
+                                let mut dropout = Dropout::new(probability, seed);
+                                bpers::encode_bytes_with_dropout(
+                                    &input,
+                                    vocab,
+                                    &allowed,
+                                    &mut dropout,
+                                )
+                            }
+                            (_, Some(_)) => {
+                                eprintln!("Only BPE vocabularies support --dropout");
+                                std::process::exit(1);
+                            }
+                            (_, None) => model.encode_bytes_with_special(&input, &allowed),
+                        };
+                        match encoded {
                             Ok(encoded) => encoded,
                             Err(err) => {
                                 eprintln!("Encoding failed: {err}");
@@ -156,9 +666,16 @@ fn main() {
                     }
                 },
                 None => {
+                    let Ok(input) = std::str::from_utf8(&input) else {
+                        eprintln!("Input is not valid UTF-8");
+                        std::process::exit(1);
+                    };
                     println!("Learning and encoding");
-                    let encoded_artifact = vocab.learn(&input, n_merges);
-                    if let Err(err) = save_vocab(&vocab, &PathBuf::from(DEFAULT_VOCAB_OUT)) {
+                    let mut vocab = Vocabulary::new();
+                    let encoded_artifact = vocab.learn(input, n_merges);
+                    if let Err(err) =
+                        save_model(&Model::Bpe(vocab), &PathBuf::from(DEFAULT_VOCAB_OUT))
+                    {
                         eprintln!("Failed to save learned vocabulary: {err}");
                     };
                     encoded_artifact
@@ -176,6 +693,8 @@ fn main() {
             input,
             vocabulary_path,
             out,
+            skip_special,
+            lines,
         } => {
             let contents = match std::fs::read_to_string(input) {
                 Ok(contents) => contents,
@@ -185,23 +704,54 @@ fn main() {
                 }
             };
 
-            let encoded = contents.chars().map(|c| c as u32).collect::<Vec<_>>();
-
-            let vocab = match load_vocab(&vocabulary_path) {
-                Ok(vocab) => vocab,
+            let model = match load_model(&vocabulary_path) {
+                Ok(model) => model,
                 Err(err) => {
                     eprintln!("Failed to load vocabulary: {err}");
                     std::process::exit(1);
                 }
             };
 
-            println!("Decoding\n");
-            let decoded = match bpers::decode(&encoded, &vocab) {
-                Ok(decoded) => decoded,
-                Err(err) => {
-                    eprintln!("Decoding failed: {err}");
+            let decoded = if lines {
+                let encoded = match parse_encoded_lines(&contents) {
+                    Ok(encoded) => encoded,
+                    Err(err) => {
+                        eprintln!("Failed to parse encoded lines: {err}");
+                        std::process::exit(1);
+                    }
+                };
+
+                println!("Decoding {} lines\n", encoded.len());
+                let documents = model
+                    .decode_bytes_batch_with(&encoded, skip_special)
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, result)| {
+                        result
+                            .inspect_err(|err| eprintln!("Decoding line {} failed: {err}", i + 1))
+                            .ok()
+                    })
+                    .collect::<Vec<_>>();
+                if documents.len() < encoded.len() {
                     std::process::exit(1);
                 }
+                let mut decoded = Vec::new();
+                for document in documents {
+                    decoded.extend(document);
+                    decoded.push(b'\n');
+                }
+                decoded
+            } else {
+                let encoded = contents.chars().map(|c| c as u32).collect::<Vec<_>>();
+
+                println!("Decoding\n");
+                match model.decode_bytes_with(&encoded, skip_special) {
+                    Ok(decoded) => decoded,
+                    Err(err) => {
+                        eprintln!("Decoding failed: {err}");
+                        std::process::exit(1);
+                    }
+                }
             };
 
             match out {
@@ -210,7 +760,12 @@ fn main() {
                         eprintln!("Failed to save decoded data: {err}")
                     }
                 }
-                None => println!("{decoded}"),
+                None => {
+                    if let Err(err) = std::io::stdout().write_all(&decoded) {
+                        eprintln!("Failed to print decoded data: {err}")
+                    }
+                    println!();
+                }
             };
         }
         CliCommand::Example => {
@@ -251,6 +806,9 @@ fn main() {
                             bpers::Token::Lonely(lone) => {
                                 char::from_u32(lone.0).unwrap().to_string()
                             }
+                            bpers::Token::Special(special) => {
+                                vocab.special_token_text(special.0).unwrap().to_string()
+                            }
                         },
                     )
                 })
@@ -295,37 +853,133 @@ fn main() {
     };
 }
 
-fn save_vocab(vocab: &Vocabulary, to: &Path) -> Result<()> {
+fn parse_category_pair(arg: &str) -> Result<(CategoryKind, CategoryKind), String> {
+    let Some((a, b)) = arg.split_once('+') else {
+        return Err("expected two categories joined by '+', e.g. letter+whitespace".to_string());
+    };
+    Ok((
+        CategoryKind::from_str(a, true)?,
+        CategoryKind::from_str(b, true)?,
+    ))
+}
+
+fn parse_probability(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(probability) if (0.0..=1.0).contains(&probability) => Ok(probability),
+        Ok(_) => Err("must be within 0 and 1".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn save_model(model: &Model, to: &Path) -> Result<()> {
     println!("Saving vocabulary to {}", to.display());
-    let mut file = File::create(to)?;
-    _ = bincode::encode_into_std_write(vocab, &mut file, bincode::config::standard())?;
+    model.save(to)?;
     Ok(())
 }
 
-fn load_vocab(from: &Path) -> Result<Vocabulary> {
+fn load_model(from: &Path) -> Result<Model> {
     println!("Loading vocabulary from {}", from.display());
-    let mut file = File::open(from)?;
-    let vocab = bincode::decode_from_std_read(&mut file, bincode::config::standard())?;
-    Ok(vocab)
+    Ok(Model::load(from)?)
 }
 
 fn save_encoded(data: &[u32], to: &Path) -> Result<()> {
     println!("Saving encoded data to {}", to.display());
-    let chars = data
-        .iter()
-        .map(|&c| char::from_u32(c).unwrap())
-        .collect::<Vec<_>>();
-
     let mut file = std::fs::File::create(to)?;
-    for c in chars {
-        file.write_all(c.encode_utf8(&mut [0; 4]).as_bytes())?;
+    write_encoded(&mut file, data)?;
+    Ok(())
+}
+
+/// Writes every token id as the UTF-8 encoded character with that code.
+fn write_encoded(writer: &mut impl Write, data: &[u32]) -> std::io::Result<()> {
+    for &id in data {
+        let c = char::from_u32(id).unwrap();
+        writer.write_all(c.encode_utf8(&mut [0; 4]).as_bytes())?;
     }
     Ok(())
 }
 
-fn save_decoded(data: &str, to: &Path) -> Result<()> {
+fn save_encoded_lines(documents: &[Vec<u32>], to: &Path) -> Result<()> {
+    println!("Saving encoded lines to {}", to.display());
+    let mut file = std::io::BufWriter::new(std::fs::File::create(to)?);
+    write_encoded_lines(documents, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes the token ids of every document as a line of space separated numbers.
+fn write_encoded_lines(documents: &[Vec<u32>], mut writer: impl Write) -> Result<()> {
+    for document in documents {
+        let line = document
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(writer, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Parses the output of `encode --lines`, a line of space separated token ids per document.
+fn parse_encoded_lines(contents: &str) -> Result<Vec<Vec<u32>>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            line.split_whitespace()
+                .map(|id| {
+                    id.parse::<u32>()
+                        .map_err(|err| anyhow::anyhow!("line {}: {err}", i + 1))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Splits the input into lines, leaving out the line terminators.
+fn split_lines(input: &[u8]) -> Vec<&[u8]> {
+    if input.is_empty() {
+        return Vec::new();
+    }
+    input
+        .strip_suffix(b"\n")
+        .unwrap_or(input)
+        .split(|&byte| byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .collect()
+}
+
+fn save_decoded(data: &[u8], to: &Path) -> Result<()> {
     println!("Saving decoded data to {}", to.display());
     let mut file = std::fs::File::create(to)?;
-    file.write_all(data.as_bytes())?;
+    file.write_all(data)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_lines_round_trip() {
+        let input = b"the cat\n\n\nsat\r\non\r\n";
+        let lines = split_lines(input);
+        assert_eq!(lines, [&b"the cat"[..], b"", b"", b"sat", b"on"]);
+
+        let documents = lines
+            .iter()
+            .map(|line| line.iter().map(|&byte| byte as u32).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut written = Vec::new();
+        write_encoded_lines(&documents, &mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert_eq!(parse_encoded_lines(&written).unwrap(), documents);
+        assert_eq!(
+            parse_encoded_lines(&written.replace('\n', "\r\n")).unwrap(),
+            documents
+        );
+
+        assert_eq!(split_lines(b"\n"), [b""]);
+        assert!(split_lines(b"").is_empty());
+        assert!(parse_encoded_lines("1 2\n3 x\n").is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// Runs the CLI in `dir`.
+fn run(dir: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bpe"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .expect("CLI runs")
+}
+
+/// Runs the CLI in `dir` and panics if it fails.
+fn bpe(dir: &PathBuf, args: &[&str]) {
+    let output = run(dir, args);
+    assert!(
+        output.status.success(),
+        "bpe {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn wordpiece_round_trips_a_sentence_with_default_options() {
+    let dir = std::env::temp_dir().join(format!("bpe-cli-wordpiece-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("corpus.txt"),
+        "the quick brown fox jumps over the lazy dog\n".repeat(20),
+    )
+    .unwrap();
+
+    bpe(
+        &dir,
+        &["learn", "corpus.txt", "--model", "wordpiece", "-s", "200"],
+    );
+    bpe(
+        &dir,
+        &[
+            "encode",
+            "the quick brown fox",
+            "out.txt",
+            "-v",
+            "vocab.bin",
+        ],
+    );
+    bpe(
+        &dir,
+        &["decode", "out.txt", "-v", "vocab.bin", "-o", "decoded.txt"],
+    );
+
+    let encoded = fs::read_to_string(dir.join("out.txt")).unwrap();
+    assert!(encoded.chars().count() > 1);
+    let decoded = fs::read_to_string(dir.join("decoded.txt")).unwrap();
+    assert_eq!(decoded, "the quick brown fox");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn lines_fail_as_a_whole_if_a_line_cannot_be_encoded() {
+    let dir = std::env::temp_dir().join(format!("bpe-cli-lines-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("corpus.txt"),
+        "the cat sat on the mat\n".repeat(20),
+    )
+    .unwrap();
+    fs::write(dir.join("input.txt"), "the cat\r\n\nsat on the mat\n").unwrap();
+
+    bpe(
+        &dir,
+        &["learn", "corpus.txt", "--model", "unigram", "-s", "30"],
+    );
+    let encode_lines = [
+        "encode",
+        "input.txt",
+        "out.txt",
+        "-v",
+        "vocab.bin",
+        "--lines",
+    ];
+    bpe(&dir, &encode_lines);
+    bpe(
+        &dir,
+        &[
+            "decode",
+            "out.txt",
+            "-v",
+            "vocab.bin",
+            "--lines",
+            "-o",
+            "decoded.txt",
+        ],
+    );
+    let decoded = fs::read_to_string(dir.join("decoded.txt")).unwrap();
+    assert_eq!(decoded, "the cat\n\nsat on the mat\n");
+
+    fs::remove_file(dir.join("out.txt")).unwrap();
+    fs::write(dir.join("input.txt"), "the cat\nthe dog\n").unwrap();
+    assert!(!run(&dir, &encode_lines).status.success());
+    assert!(!dir.join("out.txt").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn non_bpe_models_reject_bpe_only_options() {
+    let dir = std::env::temp_dir().join(format!("bpe-cli-options-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("corpus.txt"), "the cat sat on the mat\n").unwrap();
+
+    for (model, option) in [
+        ("wordpiece", ["--force", "cat"].as_slice()),
+        ("wordpiece", &["--merges", "10"]),
+        ("unigram", &["--max-token-len", "3"]),
+        ("unigram", &["--min-frequency", "3"]),
+    ] {
+        let mut args = vec!["learn", "corpus.txt", "--model", model];
+        args.extend(option);
+        let output = run(&dir, &args);
+        assert!(!output.status.success(), "bpe {args:?} succeeded");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(option[0]), "{stderr}");
+    }
+    assert!(!dir.join("vocab.bin").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}